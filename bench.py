@@ -123,6 +123,47 @@ def ask_only():
     return t1
 
 
+def recv_until_end(s, nresults=1):
+    res = bytearray()
+    start = time.time()
+    while res.count(b'_end=') < nresults and time.time() - start < 10:
+        res += s.recv(65536)
+    return [line for line in bytes(res).splitlines()
+            if not line.startswith(b'_end=')]
+
+
+def ask_prefix():
+    mains, _subs = connect(1, 0)
+
+    ncat = 100
+    mains[0].sendall(b''.join(b'ben/%s/c%03d/k%06d=%s\n' % (rnd_key, i % ncat, i, rnd_key)
+                              for i in range(opts.n)))
+    mains[0].sendall(b'_options=pipeline\n')
+    mains[0].sendall(b'ben/%s/c*\n' % rnd_key)
+    assert len(recv_until_end(mains[0])) == opts.n + 1  # with options reply
+    t1 = time.time()
+    mains[0].sendall(b''.join(b'_glob*ben/%s/c%03d/*\n' % (rnd_key, c)
+                              for c in range(ncat)))
+    assert len(recv_until_end(mains[0], ncat)) == opts.n
+    return t1
+
+
+def ask_substring():
+    mains, _subs = connect(1, 0)
+
+    ncat = 100
+    mains[0].sendall(b''.join(b'ben/%s/c%03d/k%06d=%s\n' % (rnd_key, i % ncat, i, rnd_key)
+                              for i in range(opts.n)))
+    mains[0].sendall(b'_options=pipeline\n')
+    mains[0].sendall(b'ben/%s/c*\n' % rnd_key)
+    assert len(recv_until_end(mains[0])) == opts.n + 1  # with options reply
+    t1 = time.time()
+    mains[0].sendall(b''.join(b'ben/%s/c%03d/*\n' % (rnd_key, c)
+                              for c in range(ncat)))
+    assert len(recv_until_end(mains[0], ncat)) == opts.n
+    return t1
+
+
 def ask_history():
     mains, _subs = connect(opts.s, 0)
 
@@ -0,0 +1,212 @@
+// -----------------------------------------------------------------------------
+// A Rust implementation of the NICOS cache server.
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// -----------------------------------------------------------------------------
+//
+//
+//! Aggregation of history values into time buckets.
+
+/// Function that combines the values within one bucket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Function {
+    /// The last value, which need not be numeric.
+    Last,
+    /// The minimum of the numeric values.
+    Min,
+    /// The maximum of the numeric values.
+    Max,
+    /// The minimum and the maximum, as two values for the bucket.
+    MinMax,
+    /// The mean of the numeric values.
+    Mean,
+}
+
+/// Parameters of an aggregated history query.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aggregation {
+    /// Length of the buckets in seconds.
+    pub interval: f64,
+    /// How to combine the values of a bucket.
+    pub function: Function,
+}
+
+impl Aggregation {
+    /// Parse the `interval,function` value of a history query.
+    pub fn parse(spec: &str) -> Result<Aggregation, String> {
+        let (interval, function) = spec.split_once(',').unwrap_or((spec, "last"));
+        let interval = match interval.trim().parse::<f64>() {
+            Ok(interval) if interval > 0. && interval.is_finite() => interval,
+            _ => return Err(format!("invalid aggregation interval {:?}", interval)),
+        };
+        let function = match function.trim() {
+            "last" => Function::Last,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            "minmax" => Function::MinMax,
+            "mean" => Function::Mean,
+            _ => return Err(format!("invalid aggregation function {:?}", function)),
+        };
+        Ok(Aggregation { interval, function })
+    }
+
+    /// Get the start time of the bucket with the given index.
+    pub fn bucket_start(&self, from: f64, index: f64) -> f64 {
+        from + index * self.interval
+    }
+
+    /// Get the index of the bucket that a time falls into.
+    pub fn bucket_index(&self, from: f64, time: f64) -> f64 {
+        ((time - from) / self.interval).floor()
+    }
+
+    /// Send the result for a bucket from the statistics of its numeric values.
+    pub fn send_numeric(&self, time: f64, min: f64, max: f64, mean: f64,
+                        send: &mut dyn FnMut(f64, &str)) {
+        match self.function {
+            Function::Last => (),
+            Function::Min => send(time, &min.to_string()),
+            Function::Max => send(time, &max.to_string()),
+            Function::MinMax => {
+                send(time, &min.to_string());
+                send(time, &max.to_string());
+            }
+            Function::Mean => send(time, &mean.to_string()),
+        }
+    }
+}
+
+/// Parse a value as a number, for the numeric aggregation functions and
+/// deadband filters.
+///
+/// Like a cast in Postgres, only ASCII whitespace is ignored, and values out
+/// of range are not numbers.
+pub fn numeric(value: &str) -> Option<f64> {
+    let value = value.trim_matches(|c: char| c.is_ascii_whitespace());
+    let num = value.parse().ok().filter(|v: &f64| v.is_finite())?;
+    // a nonzero value that is parsed as zero has underflowed
+    let mantissa = value.split(['e', 'E']).next().unwrap_or_default();
+    if num == 0. && mantissa.bytes().any(|b| (b'1'..=b'9').contains(&b)) {
+        return None;
+    }
+    Some(num)
+}
+
+/// Aggregates history values, which must be given ordered by time.
+///
+/// Each bucket is sent with its start time, and only if it contains values.
+pub struct Aggregator<'a> {
+    agg:       Aggregation,
+    from:      f64,
+    send:      &'a mut dyn FnMut(f64, &str),
+    /// Index of the current bucket.
+    bucket:    f64,
+    /// Time of the last value added.
+    last_time: f64,
+    /// Statistics of the current bucket.
+    last:      String,
+    count:     usize,
+    min:       f64,
+    max:       f64,
+    sum:       f64,
+}
+
+impl<'a> Aggregator<'a> {
+    pub fn new(agg: Aggregation, from: f64, send: &'a mut dyn FnMut(f64, &str)) -> Self {
+        Aggregator { agg, from, send, bucket: f64::NAN, last_time: f64::NEG_INFINITY,
+                     last: String::new(), count: 0, min: f64::INFINITY,
+                     max: f64::NEG_INFINITY, sum: 0. }
+    }
+
+    pub fn add(&mut self, time: f64, value: &str) {
+        // values that are not newer are copies, e.g. from a store rollover
+        if time <= self.last_time {
+            return;
+        }
+        self.last_time = time;
+        let bucket = self.agg.bucket_index(self.from, time);
+        if bucket != self.bucket {
+            self.flush();
+            self.bucket = bucket;
+        }
+        if self.agg.function == Function::Last {
+            self.last.clear();
+            self.last.push_str(value);
+            self.count += 1;
+        } else if let Some(num) = numeric(value) {
+            self.min = self.min.min(num);
+            self.max = self.max.max(num);
+            self.sum += num;
+            self.count += 1;
+        }
+    }
+
+    /// Send the current bucket, if it has any values.
+    fn flush(&mut self) {
+        if self.count == 0 {
+            return;
+        }
+        let time = self.agg.bucket_start(self.from, self.bucket);
+        if self.agg.function == Function::Last {
+            (self.send)(time, &self.last);
+        } else {
+            self.agg.send_numeric(time, self.min, self.max, self.sum / self.count as f64,
+                                  &mut *self.send);
+        }
+        self.count = 0;
+        self.min = f64::INFINITY;
+        self.max = f64::NEG_INFINITY;
+        self.sum = 0.;
+    }
+
+    /// Send the last bucket.
+    pub fn finish(mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate() {
+        assert!(Aggregation::parse("0,mean").is_err());
+        assert!(Aggregation::parse("10,median").is_err());
+        assert_eq!(Aggregation::parse("10").unwrap().function, Function::Last);
+
+        let run = |spec: &str| {
+            let mut res = Vec::new();
+            let mut send = |time, value: &str| res.push((time, value.to_string()));
+            let mut aggregator = Aggregator::new(Aggregation::parse(spec).unwrap(), 100.,
+                                                 &mut send);
+            for (time, value) in [(101., "1"), (105., "x"), (109., "3"), (109., "3"),
+                                  (115., "5"), (131., "-2"), (135., "")] {
+                aggregator.add(time, value);
+            }
+            aggregator.finish();
+            res
+        };
+        let res = |items: &[(f64, &str)]| items.iter().map(|&(t, v)| (t, v.to_string()))
+                                               .collect::<Vec<_>>();
+        assert_eq!(run("10,last"), res(&[(100., "3"), (110., "5"), (130., "")]));
+        assert_eq!(run("10,mean"), res(&[(100., "2"), (110., "5"), (130., "-2")]));
+        assert_eq!(run("20,minmax"), res(&[(100., "1"), (100., "5"), (120., "-2"), (120., "-2")]));
+    }
+}
@@ -24,105 +24,502 @@
 
 use std::io;
 use std::sync::Arc;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, BinaryHeap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Bound::{Included, Unbounded};
 use log::{info, debug};
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use hashbrown::{HashSet, HashMap, hash_map::Entry as HEntry};
 use crossbeam_channel::Sender;
 use mlzutil::time::localtime;
 
-use crate::entry::{Entry, UpdaterEntry, BATCHSIZE, split_key, construct_key};
-use crate::handler::UpdaterMsg;
+use crate::entry::{Entry, UpdaterEntry, KeyPattern, CategoryMatch, BATCHSIZE, split_key,
+                   construct_key};
+use crate::handler::{UpdaterMsg, ReplyTo};
 use crate::server::ClientAddr;
-use crate::message::CacheMsg::{TellTS, LockRes};
+use crate::message::CacheMsg::{TellTS, LockRes, Resume};
+use crate::retention::Retention;
+use crate::aggregate::{Aggregation, Aggregator};
+use crate::subscription::SubOptions;
 
 pub type EntryMap = HashMap<String, HashMap<String, Entry>>;
 
+/// Number of independently locked shards of the entry map.
+const NUM_SHARDS: usize = 16;
+
+/// Number of superseded expiries tolerated before the expiry queue is rebuilt.
+const STALE_EXPIRIES: usize = 1000;
+
+/// Number of recent updates kept for clients that resume after reconnecting.
+const REPLAY_LEN: usize = 10000;
+
 /// Represents the database of key-value entries.
 ///
 /// The database object is split into the part that deals with in-memory store
 /// of the current key-value set and the part that deals with storing the history
 /// and querying past values.  The latter part (`Store`) is factored out into
 /// a trait and pluggable.
+///
+/// The in-memory part is split into shards by category, each behind its own
+/// read/write lock, so that clients working on unrelated categories do not
+/// block each other.  To avoid deadlocks, locks are always taken in the order
+/// rewrites, shards (in ascending index, at most one unless rolling over the
+/// store or taking a snapshot), store, replay log.
 pub struct DB {
     /// Store backend (dynamically dispatched).
-    store:        Box<dyn Store>,
+    store:        Mutex<Box<dyn Store>>,
+    /// Reader for history queries that does not need the store lock, if the
+    /// store backend provides one.
+    history:      Option<Box<dyn HistoryReader>>,
+    /// Shards of the entry map, selected by a hash of the category.
+    shards:       Vec<RwLock<Shard>>,
+    /// Map of lock entries.
+    locks:        Mutex<HashMap<String, Entry>>,
+    /// Maps of prefix rewrite entries.
+    rewrites:     RwLock<Rewrites>,
+    /// Queue to send updates back to the updater thread.
+    upd_q:        Sender<UpdaterMsg>,
+    /// Recent updates, with their sequence numbers.
+    replay:       Mutex<ReplayLog>,
+}
+
+pub type ThreadsafeDB = Arc<DB>;
+
+/// One independently locked part of the in-memory database.
+#[derive(Default)]
+struct Shard {
     /// Map of keys, first by categories (key prefixes) then by subkey.
     entry_map:    EntryMap,
-    /// Map of lock entries.
-    locks:        HashMap<String, Entry>,
+    /// Sorted set of all full keys in the entry map, for pattern queries.
+    key_index:    BTreeSet<String>,
+    /// Queue of entries with a TTL, ordered by the time they expire.
+    expiries:     BinaryHeap<Reverse<Expiry>>,
+}
+
+/// The most recent updates sent to the updater thread.
+///
+/// Sequence numbers start at the startup time in microseconds, so that numbers
+/// from before a restart are never mistaken for current ones.
+struct ReplayLog {
+    /// Sequence number of the last update.
+    last_seq: u64,
+    /// Buffer of the last `REPLAY_LEN` updates.
+    buffer:   VecDeque<Arc<UpdaterEntry>>,
+}
+
+/// Prefix rewrite entries.
+#[derive(Default)]
+struct Rewrites {
     /// Map of rewrite entries (from X to (Y1, Y2, ...)).
     rewrites:     HashMap<String, HashSet<String>>,
     /// Inverse map of rewrite entries (from Y1 to X).
     inv_rewrites: HashMap<String, String>,
-    /// Queue to send updates back to the updater thread.
-    upd_q:        Sender<UpdaterMsg>,
 }
 
-pub type ThreadsafeDB = Arc<Mutex<DB>>;
+/// Marks the time at which an entry with a TTL expires.
+///
+/// Entries can be updated after they are queued, so the entry must be checked
+/// again when its deadline has passed.
+struct Expiry {
+    deadline: f64,
+    catname:  String,
+    subkey:   String,
+}
+
+impl Ord for Expiry {
+    fn cmp(&self, other: &Expiry) -> Ordering {
+        self.deadline.total_cmp(&other.deadline)
+    }
+}
+
+impl PartialOrd for Expiry {
+    fn partial_cmp(&self, other: &Expiry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Expiry {
+    fn eq(&self, other: &Expiry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Expiry {}
+
+/// Maximum number and order of the values returned by a history query.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HistLimit {
+    /// Maximum number of values, or None for all.
+    pub count:        Option<usize>,
+    /// Return the newest values first?
+    pub newest_first: bool,
+}
+
+/// Options of a history query, given as its value.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HistOptions {
+    pub limit: HistLimit,
+    pub agg:   Option<Aggregation>,
+}
+
+impl HistOptions {
+    /// Parse a comma-separated list of `limit=N`, `newest`, `oldest` and the
+    /// `interval[,function]` of an aggregation.
+    pub fn parse(spec: &str) -> Result<HistOptions, String> {
+        let mut opts = HistOptions::default();
+        let mut agg = Vec::new();
+        for token in spec.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            match token.split_once('=') {
+                Some(("limit", count)) => match count.trim().parse() {
+                    Ok(count) if count > 0 => opts.limit.count = Some(count),
+                    _ => return Err(format!("invalid history limit {:?}", count)),
+                },
+                _ if token == "newest" => opts.limit.newest_first = true,
+                _ if token == "oldest" => opts.limit.newest_first = false,
+                _ => agg.push(token),
+            }
+        }
+        if !agg.is_empty() {
+            opts.agg = Some(Aggregation::parse(&agg.join(","))?);
+        }
+        Ok(opts)
+    }
+}
+
+/// Applies a `HistLimit` to history values that are read in chunks, such as
+/// the days of a store.
+pub struct LimitedSender<'a> {
+    limit:     HistLimit,
+    remaining: usize,
+    /// Last value sent, to skip exact duplicates (e.g. copies at rollover).
+    last:      Option<(f64, String)>,
+    send:      &'a mut dyn FnMut(f64, &str),
+}
+
+impl<'a> LimitedSender<'a> {
+    pub fn new(limit: HistLimit, send: &'a mut dyn FnMut(f64, &str)) -> Self {
+        LimitedSender { limit, remaining: limit.count.unwrap_or(usize::MAX), last: None, send }
+    }
+
+    /// Send a single value.  Values must be given in the requested order.
+    /// Returns whether more values are wanted.
+    pub fn send(&mut self, time: f64, value: &str) -> bool {
+        if self.remaining == 0 {
+            return false;
+        }
+        match &mut self.last {
+            Some(last) if last.0 == time && last.1 == value => return true,
+            Some(last) => {
+                last.0 = time;
+                last.1.clear();
+                last.1.push_str(value);
+            }
+            None => self.last = Some((time, value.into())),
+        }
+        (self.send)(time, value);
+        self.remaining -= 1;
+        self.remaining > 0
+    }
+
+    /// Send a chunk of values, which is ordered by time.  Chunks must be given
+    /// in the requested order.  Returns whether more values are wanted.
+    pub fn send_chunk(&mut self, mut values: Vec<(f64, String)>) -> bool {
+        if self.limit.newest_first {
+            values.reverse();
+        }
+        for (time, value) in values {
+            if !self.send(time, &value) {
+                break;
+            }
+        }
+        self.remaining > 0
+    }
+}
 
 pub trait Store : Send {
     /// Clear all stored data.  Used for --clear invocation.
     fn clear(&mut self) -> io::Result<()>;
     /// Load latest key-value set from stored data.
     fn load_latest(&mut self, entry_map: &mut EntryMap) -> io::Result<()>;
-    /// Called when a new key is set.  Used to roll over stores or similar.
-    fn tell_hook(&mut self, entry: &Entry, entry_map: &mut EntryMap) -> io::Result<()>;
+    /// Check if the store must be rolled over before saving an entry with the
+    /// given timestamp.
+    fn needs_rollover(&self, _time: f64) -> bool {
+        false
+    }
+    /// Roll over the store, given the timestamp that needed it and the current
+    /// entries of all categories (spread over several maps).
+    fn rollover(&mut self, _time: f64, _entry_maps: &[&EntryMap]) -> io::Result<()> {
+        Ok(())
+    }
     /// Save a new entry to the store.
     fn save(&mut self, catname: &str, subkey: &str, entry: &Entry) -> io::Result<()>;
     /// Query history of entries for a specified key to given client.
-    fn query_history(&mut self, key: &str, from: f64, to: f64, send: &mut dyn FnMut(f64, &str));
+    fn query_history(&mut self, key: &str, from: f64, to: f64, limit: HistLimit,
+                     send: &mut dyn FnMut(f64, &str));
+    /// Query history of a key, aggregated into buckets.  By default, this is
+    /// computed from the full history.
+    fn query_aggregated(&mut self, key: &str, from: f64, to: f64, agg: Aggregation,
+                        send: &mut dyn FnMut(f64, &str)) {
+        let mut aggregator = Aggregator::new(agg, from, send);
+        self.query_history(key, from, to, HistLimit::default(),
+                           &mut |time, val| aggregator.add(time, val));
+        aggregator.finish();
+    }
+    /// Send all stored entries, with their full key, ordered by time at least
+    /// for each key.
+    fn export(&mut self, send: &mut dyn FnMut(&str, &Entry) -> io::Result<()>) -> io::Result<()>;
+    /// Import an entry exported from another store.
+    fn import(&mut self, key: &str, entry: &Entry) -> io::Result<()> {
+        let (catname, subkey) = split_key(key);
+        self.save(catname, subkey, entry)
+    }
+    /// Create a reader that can answer history queries concurrently with the
+    /// store saving new entries.  Without one, history queries lock the store.
+    fn history_reader(&self) -> Option<Box<dyn HistoryReader>> {
+        None
+    }
+    /// Create a pruner that removes old history according to retention rules,
+    /// concurrently with the store saving new entries.  Without one, history
+    /// is kept forever.
+    fn history_pruner(&self) -> Option<Box<dyn HistoryPruner>> {
+        None
+    }
+    /// Finish all writing before the server quits.
+    fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub trait HistoryReader : Send + Sync {
+    /// Query history of entries for a specified key to given client.
+    fn query_history(&self, key: &str, from: f64, to: f64, limit: HistLimit,
+                     send: &mut dyn FnMut(f64, &str));
+    /// Query history of a key, aggregated into buckets.  By default, this is
+    /// computed from the full history.
+    fn query_aggregated(&self, key: &str, from: f64, to: f64, agg: Aggregation,
+                        send: &mut dyn FnMut(f64, &str)) {
+        let mut aggregator = Aggregator::new(agg, from, send);
+        self.query_history(key, from, to, HistLimit::default(),
+                           &mut |time, val| aggregator.add(time, val));
+        aggregator.finish();
+    }
+}
+
+pub trait HistoryPruner : Send {
+    /// Remove or thin out history that is older than the retention rules allow.
+    /// The latest value of each key is always kept.
+    fn prune(&mut self, retention: &Retention, now: f64) -> io::Result<()>;
+}
+
+/// Determine the shard that holds a category.
+fn shard_index(catname: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    catname.hash(&mut hasher);
+    hasher.finish() as usize % NUM_SHARDS
+}
+
+impl Shard {
+    /// Insert a new key into the map and the indices.
+    fn insert(&mut self, catname: &str, subkey: &str, entry: Entry) {
+        self.key_index.insert(construct_key(catname, subkey));
+        self.entry_map.entry_ref(catname).or_default()
+                                         .insert(subkey.into(), entry);
+    }
+
+    /// Queue an entry for expiry, if it has a TTL.  The entry must already be
+    /// in the map.
+    fn queue_expiry(&mut self, catname: &str, subkey: &str, entry: &Entry) {
+        if entry.ttl != 0. && !entry.expired {
+            self.expiries.push(Reverse(Expiry { deadline: entry.time + entry.ttl,
+                                                catname: catname.into(),
+                                                subkey: subkey.into() }));
+            // each key has at most one current deadline, the rest are
+            // superseded by updates of the entry
+            if self.expiries.len() > 2 * self.key_index.len() + STALE_EXPIRIES {
+                self.rebuild_expiries();
+            }
+        }
+    }
+
+    /// Rebuild the expiry queue with only the current deadline of each entry.
+    fn rebuild_expiries(&mut self) {
+        self.expiries = self.entry_map.iter().flat_map(|(catname, catmap)| {
+            catmap.iter().filter(|(_, entry)| entry.ttl != 0. && !entry.expired)
+                         .map(move |(subkey, entry)| {
+                             Reverse(Expiry { deadline: entry.time + entry.ttl,
+                                              catname: catname.clone(),
+                                              subkey: subkey.clone() })
+                         })
+        }).collect();
+    }
+
+    /// Call the closure for all entries whose key matches the pattern.
+    fn for_each_match(&self, wc: &KeyPattern, mut f: impl FnMut(&str, &Entry)) {
+        // if the pattern has a literal prefix, only the range of keys starting
+        // with it needs to be checked
+        if let Some(prefix) = wc.literal_prefix() {
+            for fullkey in self.key_index.range::<str, _>((Included(prefix), Unbounded))
+                                         .take_while(|key| key.starts_with(prefix)) {
+                if wc.is_match(fullkey) {
+                    let (catname, subkey) = split_key(fullkey);
+                    if let Some(entry) = self.entry_map.get(catname).and_then(|m| m.get(subkey)) {
+                        f(fullkey, entry);
+                    }
+                }
+            }
+            return;
+        }
+        // otherwise, categories that cannot match are skipped as a whole
+        for (catname, catmap) in &self.entry_map {
+            let (check, subprefix) = match wc.match_category(catname) {
+                CategoryMatch::Never => continue,
+                CategoryMatch::Subkeys(prefix) => (false, prefix),
+                CategoryMatch::Check => (true, ""),
+            };
+            for (subkey, entry) in catmap {
+                if subkey.starts_with(subprefix) {
+                    let fullkey = construct_key(catname, subkey);
+                    if !check || wc.is_match(&fullkey) {
+                        f(&fullkey, entry);
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl DB {
     /// Create a new empty database.
     pub fn new(store: Box<dyn Store>, upd_q: Sender<UpdaterMsg>) -> DB {
         DB {
-            store,
+            history: store.history_reader(),
+            store: Mutex::new(store),
             upd_q,
-            entry_map: HashMap::default(),
-            locks: HashMap::default(),
-            rewrites: HashMap::default(),
-            inv_rewrites: HashMap::default(),
+            shards: (0..NUM_SHARDS).map(|_| RwLock::default()).collect(),
+            locks: Mutex::default(),
+            rewrites: RwLock::default(),
+            replay: Mutex::new(ReplayLog { last_seq: (localtime() * 1e6) as u64,
+                                           buffer: VecDeque::new() }),
+        }
+    }
+
+    /// Send an update to the updater thread, with the next sequence number.
+    ///
+    /// The replay log is only locked to number the update and record it, so
+    /// updates from different shards can reach the updater thread out of
+    /// order; it puts them back in the order of their sequence numbers.
+    fn notify(&self, fullkey: String, entry: &Entry, from: Option<ClientAddr>) {
+        let mut entry = Arc::new(UpdaterEntry::new(fullkey, entry, 0));
+        let _evicted = {
+            let mut replay = self.replay.lock();
+            replay.last_seq += 1;
+            Arc::get_mut(&mut entry).expect("new entry is shared").set_seq(replay.last_seq);
+            let evicted = if replay.buffer.len() >= REPLAY_LEN {
+                replay.buffer.pop_front()
+            } else {
+                None
+            };
+            replay.buffer.push_back(entry.clone());
+            evicted
+        };
+        let _ = self.upd_q.send(UpdaterMsg::Update(entry, from));
+    }
+
+    /// Get the sequence number of the last update.
+    pub fn last_seq(&self) -> u64 {
+        self.replay.lock().last_seq
+    }
+
+    /// Replay the updates after a sequence number to a client, followed by a
+    /// `_resume` reply: `ok`, or `resync` if some of the updates are no longer
+    /// kept.
+    pub fn resume(&self, addr: ClientAddr, seq: u64, reply: &ReplyTo) {
+        let replay = self.replay.lock();
+        let first = replay.buffer.front().map_or(replay.last_seq + 1, |entry| entry.seq());
+        if seq > replay.last_seq || seq + 1 < first {
+            let line = reply.format(&Resume { seq: "resync" });
+            let _ = self.upd_q.send(UpdaterMsg::Replay(addr, Vec::new(), line));
+        } else {
+            let skip = (seq + 1 - first) as usize;
+            let entries = replay.buffer.iter().skip(skip).cloned().collect();
+            let line = reply.format(&Resume { seq: "ok" });
+            let _ = self.upd_q.send(UpdaterMsg::Replay(addr, entries, line));
         }
     }
 
     /// Clear all DB store files.
-    pub fn clear_db(&mut self) -> io::Result<()> {
-        self.store.clear()
+    pub fn clear_db(&self) -> io::Result<()> {
+        self.store.lock().clear()
+    }
+
+    /// Let the store finish writing before quitting.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.store.lock().shutdown()
     }
 
     /// Load the DB entries from the store path.
-    pub fn load_db(&mut self) -> io::Result<()> {
-        self.store.load_latest(&mut self.entry_map)
+    pub fn load_db(&self) -> io::Result<()> {
+        let mut entry_map = EntryMap::default();
+        let result = self.store.lock().load_latest(&mut entry_map);
+        for (catname, catmap) in entry_map {
+            let mut shard = self.shards[shard_index(&catname)].write();
+            for (subkey, entry) in catmap {
+                shard.insert(&catname, &subkey, entry.clone());
+                shard.queue_expiry(&catname, &subkey, &entry);
+            }
+        }
+        result
+    }
+
+    /// Roll over the store if needed before saving an entry with this time.
+    fn check_rollover(&self, time: f64) -> io::Result<()> {
+        if !self.store.lock().needs_rollover(time) {
+            return Ok(());
+        }
+        // the store needs to see all entries, so lock all shards first
+        let shards = self.shards.iter().map(|shard| shard.read()).collect::<Vec<_>>();
+        let maps = shards.iter().map(|shard| &shard.entry_map).collect::<Vec<_>>();
+        let mut store = self.store.lock();
+        // check again, another thread could have done it in the meantime
+        if store.needs_rollover(time) {
+            store.rollover(time, &maps)?;
+        }
+        Ok(())
     }
 
     /// Clean up expired keys.
-    pub fn clean(&mut self) {
-        for (catname, submap) in &mut self.entry_map {
-            let now = localtime();
-            for (subkey, entry) in submap.iter_mut() {
-                if entry.expired {
-                    continue;
-                }
-                if entry.ttl != 0. && (entry.time + entry.ttl < now) {
+    pub fn clean(&self) {
+        let now = localtime();
+        for shard in &self.shards {
+            let mut shard = shard.write();
+            let shard = &mut *shard;
+            while shard.expiries.peek().map_or(false, |next| next.0.deadline < now) {
+                let Expiry { catname, subkey, .. } = shard.expiries.pop().unwrap().0;
+                if let Some(entry) = shard.entry_map.get_mut(&catname).and_then(|m| m.get_mut(&subkey)) {
+                    // the entry may have been updated since it was queued
+                    if entry.expired || entry.ttl == 0. || entry.time + entry.ttl >= now {
+                        continue;
+                    }
                     debug!("cleaner: {}/{} expired", catname, subkey);
                     entry.expired = true;
-                    let fullkey = construct_key(catname, subkey);
-                    let _ = self.upd_q.send(
-                        UpdaterMsg::Update(UpdaterEntry::new(fullkey, entry), None));
-                    let _ = self.store.save(catname, subkey, entry);
+                    self.notify(construct_key(&catname, &subkey), entry, None);
+                    let _ = self.store.lock().save(&catname, &subkey, entry);
                 }
             }
         }
     }
 
     /// Set or delete a prefix rewrite entry.
-    pub fn rewrite(&mut self, new: &str, old: &str) {
+    pub fn rewrite(&self, new: &str, old: &str) {
+        let mut guard = self.rewrites.write();
+        let Rewrites { rewrites, inv_rewrites } = &mut *guard;
         // rewrite goes old -> new
         let old = old.to_lowercase();
         // remove any existing rewrite to the "new" prefix
-        if let Some(previous) = self.inv_rewrites.remove(new) {
-            if let HEntry::Occupied(mut entry) = self.rewrites.entry(previous) {
+        if let Some(previous) = inv_rewrites.remove(new) {
+            if let HEntry::Occupied(mut entry) = rewrites.entry(previous) {
                 entry.get_mut().remove(new);
                 if entry.get().is_empty() {
                     entry.remove();
@@ -131,114 +528,166 @@ impl DB {
         }
         // then, if old is not empty, insert a new rewrite
         if !old.is_empty() {
-            self.inv_rewrites.insert(new.into(), old.clone());
-            self.rewrites.entry(old).or_insert_with(HashSet::new).insert(new.into());
+            inv_rewrites.insert(new.into(), old.clone());
+            rewrites.entry(old).or_insert_with(HashSet::new).insert(new.into());
         }
-        info!("rewrites={:?} inv_rewrites={:?}",
-              self.rewrites, self.inv_rewrites);
+        info!("rewrites={:?} inv_rewrites={:?}", rewrites, inv_rewrites);
     }
 
     /// Insert or update a key-value entry.
-    pub fn tell(&mut self, key: &str, val: &str, time: f64, ttl: f64, no_store: bool,
+    pub fn tell(&self, key: &str, val: &str, time: f64, ttl: f64, no_store: bool,
                 from: ClientAddr) -> io::Result<()> {
         let (catname, subkey) = split_key(key);
         let mut newcats = vec![catname];
         // process rewrites for this key's prefix (= category)
-        if let Some(rewrite_cats) = self.rewrites.get(catname) {
+        let rewrites = self.rewrites.read();
+        if let Some(rewrite_cats) = rewrites.rewrites.get(catname) {
             newcats.extend(rewrite_cats.iter().map(String::as_str));
         }
         let entry = Entry::new(time, ttl, val);
-        self.store.tell_hook(&entry, &mut self.entry_map)?;
+        if !no_store {
+            self.check_rollover(time)?;
+        }
         for catname in newcats {
             let mut need_update = true;
+            let mut shard = self.shards[shard_index(catname)].write();
             // write to in-memory map
-            if let Some(catmap) = self.entry_map.get_mut(catname) {
-                if let Some(existing_entry) = catmap.get_mut(subkey) {
-                    if existing_entry.value == val && !existing_entry.expired {
-                        // if we already have the same value, only adapt time
-                        // and ttl info
+            if let Some(existing_entry) = shard.entry_map.get_mut(catname)
+                                                         .and_then(|m| m.get_mut(subkey)) {
+                if existing_entry.value == val && !existing_entry.expired {
+                    // if we already have the same value, only adapt time
+                    // and ttl info
+                    need_update = false;
+                    existing_entry.time = time;
+                    existing_entry.ttl = ttl;
+                } else {
+                    if val.is_empty() && existing_entry.expired {
+                        // if the value is deleted, but the entry was already
+                        // expired, no need to record the deletion
                         need_update = false;
-                        existing_entry.time = time;
-                        existing_entry.ttl = ttl;
-                    } else {
-                        if val.is_empty() && existing_entry.expired {
-                            // if the value is deleted, but the entry was already
-                            // expired, no need to record the deletion
-                            need_update = false;
-                        }
-                        *existing_entry = entry.clone();
                     }
-                } else {
-                    catmap.insert(subkey.into(), entry.clone());
+                    *existing_entry = entry.clone();
                 }
             } else {
-                let mut catmap = HashMap::default();
-                catmap.insert(subkey.into(), entry.clone());
-                self.entry_map.insert(catname.into(), catmap);
+                shard.insert(catname, subkey, entry.clone());
             }
+            shard.queue_expiry(catname, subkey, &entry);
             // write to on-disk file
             if need_update && !no_store {
-                self.store.save(catname, subkey, &entry)?;
+                self.store.lock().save(catname, subkey, &entry)?;
             }
             // notify about update (nostore keys are always propagated)
             if need_update || no_store {
-                let fullkey = construct_key(catname, subkey);
-                let _ = self.upd_q.send(
-                    UpdaterMsg::Update(UpdaterEntry::new(fullkey, &entry), Some(from)));
+                self.notify(construct_key(catname, subkey), &entry, Some(from));
             }
         }
         Ok(())
     }
 
     /// Ask for a single value.
-    pub fn ask(&self, key: &str, with_ts: bool, send_q: &Sender<String>) {
+    pub fn ask(&self, key: &str, with_ts: bool, reply: &ReplyTo) {
         let (catname, subkey) = split_key(key);
-        let msg = match self.entry_map.get(catname).and_then(|m| m.get(subkey)) {
+        let shard = self.shards[shard_index(catname)].read();
+        let msg = match shard.entry_map.get(catname).and_then(|m| m.get(subkey)) {
             None => Entry::no_msg(key, with_ts),
             Some(entry) => entry.to_msg(key, with_ts),
         };
-        let _ = send_q.send(msg.to_string());
+        reply.send(&msg);
     }
 
-    /// Ask for many values matching a key wildcard.
-    pub fn ask_wc(&self, wc: &str, with_ts: bool, send_q: &Sender<String>) {
+    /// Ask for many values matching a key pattern.
+    pub fn ask_wc(&self, wc: &KeyPattern, with_ts: bool, reply: &ReplyTo) {
         let mut res = Vec::with_capacity(BATCHSIZE);
-        for (catname, catmap) in &self.entry_map {
-            for (subkey, entry) in catmap.iter() {
-                let fullkey = construct_key(catname, subkey);
-                if fullkey.contains(wc) {
-                    res.push(entry.to_msg(&fullkey, with_ts).to_string());
-                    if res.len() >= BATCHSIZE {
-                        let _ = send_q.send(res.join(""));
-                        res.clear();
-                    }
+        let mut count = 0;
+        for shard in &self.shards {
+            shard.read().for_each_match(wc, |fullkey, entry| {
+                res.push(reply.format(&entry.to_msg(fullkey, with_ts)));
+                count += 1;
+                if res.len() >= BATCHSIZE {
+                    reply.send_formatted(res.join(""));
+                    res.clear();
                 }
-            }
+            });
+        }
+        if !res.is_empty() {
+            reply.send_formatted(res.join(""));
         }
-        let _ = send_q.send(res.join(""));
+        reply.end(count);
     }
 
-    /// Ask for the history of a single key.
-    pub fn ask_hist(&mut self, key: &str, from: f64, delta: f64, send_q: &Sender<String>) {
+    /// Subscribe a client to a key pattern, and send it the matching entries
+    /// first, as `ask_wc` would.
+    ///
+    /// The entries are queued for the client's updater together with the
+    /// subscription.  Since updates are queued while holding the lock of their
+    /// shard, holding all shards meanwhile ensures that no update is missed or
+    /// sent twice.
+    pub fn subscribe_with_snapshot(&self, addr: ClientAddr, pattern: KeyPattern, with_ts: bool,
+                                   opts: SubOptions, reply: &ReplyTo) {
+        let shards = self.shards.iter().map(|shard| shard.read()).collect::<Vec<_>>();
+        let mut lines = String::new();
+        let mut count = 0;
+        for shard in &shards {
+            shard.for_each_match(&pattern, |fullkey, entry| {
+                lines.push_str(&reply.format(&entry.to_msg(fullkey, with_ts)));
+                count += 1;
+            });
+        }
+        lines.extend(reply.format_end(count));
+        if !lines.is_empty() {
+            let _ = self.upd_q.send(UpdaterMsg::Snapshot(addr, lines));
+        }
+        let _ = self.upd_q.send(UpdaterMsg::Subscription(addr, pattern, with_ts, opts));
+    }
+
+    /// Ask for the history of a single key, optionally aggregated.
+    ///
+    /// If possible, this does not lock the store, since reading the history can
+    /// take a long time.
+    pub fn ask_hist(&self, key: &str, from: f64, delta: f64, opts: HistOptions,
+                    reply: &ReplyTo) {
         if delta < 0. {
             return;
         }
         let mut res = Vec::with_capacity(BATCHSIZE);
-        self.store.query_history(key, from, from + delta, &mut |time, val| {
-            res.push(TellTS { key, val, time, ttl: 0., no_store: false }.to_string());
+        let mut count = 0;
+        let mut send = |time, val: &str| {
+            res.push(reply.format(&TellTS { key, val, time, ttl: 0., no_store: false }));
+            count += 1;
             if res.len() >= BATCHSIZE {
-                let _ = send_q.send(res.join(""));
+                reply.send_formatted(res.join(""));
                 res.clear();
             }
-        });
-        let _ = send_q.send(res.join(""));
+        };
+        let to = from + delta;
+        let HistOptions { limit, agg } = opts;
+        if let Some(agg) = agg {
+            // buckets are computed in order, the limit is applied afterwards
+            let mut buckets = Vec::new();
+            let mut add = |time, val: &str| buckets.push((time, val.to_string()));
+            match self.history {
+                Some(ref history) => history.query_aggregated(key, from, to, agg, &mut add),
+                None => self.store.lock().query_aggregated(key, from, to, agg, &mut add),
+            }
+            LimitedSender::new(limit, &mut send).send_chunk(buckets);
+        } else {
+            match self.history {
+                Some(ref history) => history.query_history(key, from, to, limit, &mut send),
+                None => self.store.lock().query_history(key, from, to, limit, &mut send),
+            }
+        }
+        if !res.is_empty() {
+            reply.send_formatted(res.join(""));
+        }
+        reply.end(count);
     }
 
     /// Lock or unlock a key for multi-process synchronization.
-    pub fn lock(&mut self, lock: bool, key: &str, client: &str, time: f64, ttl: f64,
-                send_q: &Sender<String>) {
+    pub fn lock(&self, lock: bool, key: &str, client: &str, time: f64, ttl: f64,
+                reply: &ReplyTo) {
         // find existing lock entry (these are in a different namespace from normal keys)
-        let entry = self.locks.entry(key.into());
+        let mut locks = self.locks.lock();
+        let entry = locks.entry(key.into());
         let msg = if lock {
             match entry {
                 HEntry::Occupied(mut entry) => {
@@ -248,36 +697,116 @@ impl DB {
                     };
                     if lock_denied {
                         info!("lock {}: denied to {} (locked by {})", key, client, entry.get().value);
-                        LockRes { key, client: &entry.get().value }.to_string()
+                        reply.format(&LockRes { key, client: &entry.get().value })
                     } else {
                         entry.insert(Entry::new(time, ttl, client));
                         debug!("lock {}: granted to {} (same client or lock expired)", key, client);
-                        LockRes { key, client: "" }.to_string()
+                        reply.format(&LockRes { key, client: "" })
                     }
                 },
                 HEntry::Vacant(entry) => {
                     entry.insert(Entry::new(time, ttl, client));
                     info!("lock {}: granted to {} (no lock)", key, client);
-                    LockRes { key, client: "" }.to_string()
+                    reply.format(&LockRes { key, client: "" })
                 }
             }
         } else {
             match entry {
                 HEntry::Occupied(ref entry) if entry.get().value != client => {
                     info!("unlock {}: denied to {} (locked by {})", key, client, entry.get().value);
-                    LockRes { key, client: &entry.get().value }.to_string()
+                    reply.format(&LockRes { key, client: &entry.get().value })
                 },
                 HEntry::Occupied(entry) => {
                     info!("unlock {}: granted to {} (unlocked)", key, client);
                     entry.remove();
-                    LockRes { key, client: "" }.to_string()
+                    reply.format(&LockRes { key, client: "" })
                 },
                 HEntry::Vacant(..) => {
                     info!("unlock {}: granted to {} (unnecessary)", key, client);
-                    LockRes { key, client: "" }.to_string()
+                    reply.format(&LockRes { key, client: "" })
                 }
             }
         };
-        let _ = send_q.send(msg);
+        reply.send_formatted(msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::Function;
+    use crate::message::ProtoOpts;
+
+    #[test]
+    fn hist_options() {
+        assert_eq!(HistOptions::parse("").unwrap(), HistOptions::default());
+        let opts = HistOptions::parse("limit=10,newest").unwrap();
+        assert_eq!(opts.limit, HistLimit { count: Some(10), newest_first: true });
+        assert_eq!(opts.agg, None);
+        let opts = HistOptions::parse("60,mean,limit=5").unwrap();
+        assert_eq!(opts.limit.count, Some(5));
+        assert_eq!(opts.agg, Some(Aggregation { interval: 60., function: Function::Mean }));
+        assert!(HistOptions::parse("limit=0").is_err());
+        assert!(HistOptions::parse("newest,sum").is_err());
+
+        let mut res = Vec::new();
+        let mut send = |time, val: &str| res.push((time, val.to_string()));
+        let mut sender = LimitedSender::new(HistLimit { count: Some(3), newest_first: true },
+                                            &mut send);
+        assert!(sender.send_chunk(vec![(3., "a".into()), (4., "b".into())]));
+        // the duplicate of the last sent value is skipped
+        assert!(!sender.send_chunk(vec![(1., "c".into()), (2., "d".into()), (3., "a".into())]));
+        assert_eq!(res, [(4., "b".into()), (3., "a".into()), (2., "d".into())]);
+    }
+
+    #[test]
+    fn expiry_queue() {
+        let (upd_q, _upd_r) = crossbeam_channel::unbounded();
+        let db = DB::new(Box::new(crate::store_memory::Store::new(None, 1)), upd_q);
+        let addr = "127.0.0.1:14869".parse().unwrap();
+        for i in 0..10 * STALE_EXPIRIES {
+            db.tell("a/refreshed", "1", i as f64, 3600., false, addr).unwrap();
+        }
+        let expiries = &db.shards[shard_index("a")].read().expiries;
+        assert!(expiries.len() <= STALE_EXPIRIES + 2);
+        let last = 10. * STALE_EXPIRIES as f64 + 3599.;
+        assert!(expiries.iter().any(|expiry| expiry.0.deadline == last));
+    }
+
+    #[test]
+    fn resume() {
+        let (upd_q, upd_r) = crossbeam_channel::unbounded();
+        let db = DB::new(Box::new(crate::store_memory::Store::new(None, 1)), upd_q);
+        let addr = "127.0.0.1:14869".parse().unwrap();
+        let start = db.last_seq();
+        for i in 0..REPLAY_LEN + 5 {
+            db.tell(&format!("a/k{}", i % 3), &i.to_string(), 0., 0., false, addr).unwrap();
+        }
+        let seqs: Vec<_> = upd_r.try_iter().map(|msg| match msg {
+            UpdaterMsg::Update(entry, _) => entry.seq(),
+            _ => panic!("unexpected message"),
+        }).collect();
+        assert!(seqs.iter().copied().eq(start + 1..=start + REPLAY_LEN as u64 + 5));
+
+        let (send_q, _) = crossbeam_channel::unbounded();
+        let reply = ReplyTo::new(&send_q, ProtoOpts::default(), None);
+        let replay = |seq| {
+            db.resume(addr, seq, &reply);
+            match upd_r.try_recv() {
+                Ok(UpdaterMsg::Replay(_, entries, line)) =>
+                    (entries.iter().map(|e| e.entry().value.clone()).collect::<Vec<_>>(), line),
+                _ => panic!("no replay message"),
+            }
+        };
+        let last = start + REPLAY_LEN as u64 + 5;
+        assert_eq!(replay(last - 2), (vec![(REPLAY_LEN + 3).to_string(),
+                                           (REPLAY_LEN + 4).to_string()],
+                                      "_resume=ok\n".into()));
+        assert_eq!(replay(last), (vec![], "_resume=ok\n".into()));
+        assert_eq!(replay(start + 5).0.len(), REPLAY_LEN);
+        // too old, or from before a server restart
+        assert_eq!(replay(start + 4), (vec![], "_resume=resync\n".into()));
+        assert_eq!(replay(4), (vec![], "_resume=resync\n".into()));
+        assert_eq!(replay(last + 1), (vec![], "_resume=resync\n".into()));
     }
 }
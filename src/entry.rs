@@ -23,8 +23,10 @@
 //! This module contains the definition for the in-memory and on-disk database.
 
 use std::fmt;
+use regex::Regex;
+use once_cell::sync::OnceCell;
 
-use crate::message::CacheMsg;
+use crate::message::{CacheMsg, MatchKind};
 use crate::message::CacheMsg::{Tell, TellOld, TellTS, TellOldTS};
 
 /// Number of entries to send back in one batch.
@@ -59,6 +61,12 @@ impl Entry {
         Entry { time, ttl, expired: value.is_empty(), value }
     }
 
+    /// Create an Entry as read back from a store, where only the fact that the
+    /// value was expiring is known, not its TTL.
+    pub fn from_stored(time: f64, expiring: bool, value: &str) -> Entry {
+        Entry::new(time, if expiring { f64::INFINITY } else { 0. }, value)
+    }
+
     /// Mark the Entry as expired.
     pub fn expired(mut self) -> Entry {
         self.expired = true;
@@ -82,7 +90,7 @@ impl Entry {
     }
 
     /// Return a Tell-type CacheMsg that represents a missing entry.
-    pub fn no_msg(key: &str, with_ts: bool) -> CacheMsg {
+    pub fn no_msg(key: &str, with_ts: bool) -> CacheMsg<'_> {
         if with_ts {
             TellOldTS { key, val: "", time: 0., ttl: 0. }
         } else {
@@ -111,19 +119,137 @@ pub fn construct_key(catname: &str, subkey: &str) -> String {
             subkey)
 }
 
+/// Describes which keys of a category can match a key pattern.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CategoryMatch<'a> {
+    /// No key of the category matches.
+    Never,
+    /// All keys whose subkey starts with this prefix match.
+    Subkeys(&'a str),
+    /// Each key has to be checked.
+    Check,
+}
+
+/// A key pattern for wildcard queries and subscriptions.
+///
+/// Glob and regex patterns are compiled once when the pattern is created.
+#[derive(Debug)]
+pub struct KeyPattern {
+    kind:    MatchKind,
+    pattern: String,
+    regex:   Option<Regex>,
+}
+
+impl KeyPattern {
+    pub fn new(kind: MatchKind, pattern: &str) -> Result<KeyPattern, regex::Error> {
+        let regex = match kind {
+            MatchKind::Substring => None,
+            MatchKind::Glob => Some(Regex::new(&glob_to_regex(pattern))?),
+            MatchKind::Regex => Some(Regex::new(pattern)?),
+        };
+        Ok(KeyPattern { kind, pattern: pattern.into(), regex })
+    }
+
+    pub fn kind(&self) -> MatchKind {
+        self.kind
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Return a literal prefix that all matching keys must start with, if the
+    /// pattern has one.
+    pub fn literal_prefix(&self) -> Option<&str> {
+        match self.kind {
+            MatchKind::Substring => None,
+            MatchKind::Glob => {
+                let end = self.pattern.find(&['*', '?'][..]).unwrap_or(self.pattern.len());
+                Some(&self.pattern[..end])
+            },
+            MatchKind::Regex => {
+                // only handle the simple case of a literal after a "^" anchor,
+                // without any alternation that could bypass the anchor
+                let rest = self.pattern.strip_prefix('^')?;
+                if rest.contains('|') {
+                    return None;
+                }
+                let is_meta = |c| "\\.+*?()[]{}^$".contains(c);
+                let mut end = rest.find(is_meta).unwrap_or(rest.len());
+                if rest[end..].starts_with(|c| "?*{".contains(c)) {
+                    // the last literal character is optional
+                    end = rest[..end].char_indices().last().map_or(0, |(i, _)| i);
+                }
+                Some(&rest[..end])
+            },
+        }
+    }
+
+    /// Determine which keys of a category can match, from its name alone.
+    ///
+    /// This is possible for substring patterns with a slash: they must either
+    /// lie within the category name, or span its end and the start of the subkey.
+    pub fn match_category(&self, catname: &str) -> CategoryMatch<'_> {
+        if self.kind != MatchKind::Substring {
+            return CategoryMatch::Check;
+        }
+        match self.pattern.rfind('/') {
+            None => CategoryMatch::Check,
+            // keys without category don't contain a slash
+            Some(_) if catname == "nocat" => CategoryMatch::Never,
+            Some(_) if catname.contains(&self.pattern) => CategoryMatch::Subkeys(""),
+            Some(i) if catname.ends_with(&self.pattern[..i]) =>
+                CategoryMatch::Subkeys(&self.pattern[i+1..]),
+            Some(_) => CategoryMatch::Never,
+        }
+    }
+
+    /// Check if the pattern matches a full key.
+    pub fn is_match(&self, key: &str) -> bool {
+        match self.regex {
+            Some(ref regex) => regex.is_match(key),
+            None => key.contains(&self.pattern),
+        }
+    }
+}
+
+/// Translate a glob pattern into an anchored regular expression.
+///
+/// `*` and `?` match any number of characters, or a single character, within
+/// one key component, while `**` also matches across slashes.
+fn glob_to_regex(glob: &str) -> String {
+    let mut res = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                res.push_str(".*");
+            },
+            '*' => res.push_str("[^/]*"),
+            '?' => res.push_str("[^/]"),
+            ch => res.push_str(&regex::escape(ch.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    res.push('$');
+    res
+}
+
 /// Entry associated with a key and a cache for interpolated protocol messages.
 ///
 /// This is used by updaters that have to send the same update string to
-/// potentially a lot of clients.
+/// potentially a lot of clients.  It is shared with `Arc` by updaters that
+/// hold back updates for a while.
 pub struct UpdaterEntry {
     key: String,
     val: Entry,
-    cache: (Option<String>, Option<String>),
+    seq: u64,
+    cache: [OnceCell<String>; 4],
 }
 
 impl UpdaterEntry {
-    pub fn new(key: String, val: &Entry) -> UpdaterEntry {
-        UpdaterEntry { key, val: val.clone(), cache: (None, None) }
+    pub fn new(key: String, val: &Entry, seq: u64) -> UpdaterEntry {
+        UpdaterEntry { key, val: val.clone(), seq, cache: Default::default() }
     }
 
     /// Check if the entry matches a subscription substring.
@@ -131,11 +257,25 @@ impl UpdaterEntry {
         &self.key
     }
 
+    /// Get the entry itself.
+    pub fn entry(&self) -> &Entry {
+        &self.val
+    }
+
+    /// Get the sequence number of the update.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Set the sequence number of the update.
+    pub fn set_seq(&mut self, seq: u64) {
+        self.seq = seq;
+    }
+
     /// Get the interpolated message, use the cache if possible.
-    pub fn get_msg(&mut self, with_ts: bool) -> &str {
-        let UpdaterEntry { key, val, cache } = self;
-        let cached = if with_ts { &mut cache.0 } else { &mut cache.1 };
-        cached.get_or_insert_with(|| val.to_msg(key, with_ts).to_string())
+    pub fn get_msg(&self, with_ts: bool, escape: bool) -> &str {
+        self.cache[2*(with_ts as usize) + escape as usize]
+            .get_or_init(|| self.val.to_msg(&self.key, with_ts).to_line(escape))
     }
 }
 
@@ -144,3 +284,65 @@ impl fmt::Debug for UpdaterEntry {
         write!(f, "{:?}={:?}", self.key, self.val)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::message::MatchKind;
+    use super::{KeyPattern, CategoryMatch, construct_key};
+
+    #[test]
+    fn key_patterns() {
+        let substr = KeyPattern::new(MatchKind::Substring, "motor/value").unwrap();
+        assert!(substr.is_match("nicos/motor/value_offset"));
+        let glob = KeyPattern::new(MatchKind::Glob, "nicos/*/value").unwrap();
+        assert!(glob.is_match("nicos/motor/value"));
+        assert!(!glob.is_match("nicos/motor/value_offset"));
+        assert!(!glob.is_match("nicos/a/b/value"));
+        assert!(!glob.is_match("xnicos/motor/value"));
+        let glob = KeyPattern::new(MatchKind::Glob, "nicos/**/v?lue").unwrap();
+        assert!(glob.is_match("nicos/a/b/value"));
+        let regex = KeyPattern::new(MatchKind::Regex, "^nicos/[ab]/").unwrap();
+        assert!(regex.is_match("nicos/a/value"));
+        assert!(!regex.is_match("nicos/c/value"));
+        assert!(KeyPattern::new(MatchKind::Regex, "(").is_err());
+    }
+
+    #[test]
+    fn literal_prefixes() {
+        let prefix = |kind, pattern| KeyPattern::new(kind, pattern).unwrap()
+                                                                   .literal_prefix().map(String::from);
+        assert_eq!(prefix(MatchKind::Substring, "nicos/"), None);
+        assert_eq!(prefix(MatchKind::Glob, "nicos/*/value"), Some("nicos/".into()));
+        assert_eq!(prefix(MatchKind::Glob, "nicos/m?/value"), Some("nicos/m".into()));
+        assert_eq!(prefix(MatchKind::Glob, "nicos/value"), Some("nicos/value".into()));
+        assert_eq!(prefix(MatchKind::Regex, "nicos/"), None);
+        assert_eq!(prefix(MatchKind::Regex, "^nicos/.*"), Some("nicos/".into()));
+        assert_eq!(prefix(MatchKind::Regex, "^nicos/ab?"), Some("nicos/a".into()));
+        assert_eq!(prefix(MatchKind::Regex, "^nicos/a|b"), None);
+    }
+
+    #[test]
+    fn category_matches() {
+        let check = |pattern, catname, subkey, expected| {
+            let pattern = KeyPattern::new(MatchKind::Substring, pattern).unwrap();
+            let res = pattern.match_category(catname);
+            assert_eq!(res, expected);
+            // the result must agree with checking the full key
+            let full = pattern.is_match(&construct_key(catname, subkey));
+            match res {
+                CategoryMatch::Never => assert!(!full),
+                CategoryMatch::Subkeys(prefix) => assert_eq!(subkey.starts_with(prefix), full),
+                CategoryMatch::Check => (),
+            }
+        };
+        check("nicos/dev/", "nicos/dev", "value", CategoryMatch::Subkeys(""));
+        check("dev/val", "nicos/dev", "value", CategoryMatch::Subkeys("val"));
+        check("dev/val", "nicos/dev", "status", CategoryMatch::Subkeys("val"));
+        check("os/d", "nicos/dev", "value", CategoryMatch::Subkeys(""));
+        check("dev/val", "nicos/other", "value", CategoryMatch::Never);
+        check("/value", "nocat", "value", CategoryMatch::Never);
+        check("value", "nicos/dev", "value", CategoryMatch::Check);
+        let glob = KeyPattern::new(MatchKind::Glob, "nicos/*/value").unwrap();
+        assert_eq!(glob.match_category("other"), CategoryMatch::Check);
+    }
+}
@@ -23,40 +23,70 @@
 //! This module contains the handler for a single network connection.
 
 use std::thread;
+use std::sync::Arc;
+use std::time::Instant;
 use log::{info, warn, debug};
 use memchr::memchr;
 use aho_corasick::AhoCorasick;
 use crossbeam_channel::{unbounded, Sender, Receiver};
 use mlzutil::time::localtime;
 
-use crate::entry::UpdaterEntry;
-use crate::database::ThreadsafeDB;
-use crate::message::CacheMsg;
+use crate::entry::{UpdaterEntry, KeyPattern};
+use crate::database::{ThreadsafeDB, HistOptions};
+use crate::message::{CacheMsg, MatchKind, ProtoOpts, SEQ_KEY};
 use crate::message::CacheMsg::*;
 use crate::server::{ClientAddr, Client, RECVBUF_LEN};
+use crate::outqueue::{OutQueue, QueueOptions};
+use crate::subscription::{SubOptions, RateLimiter, DeadbandFilter};
 
 
 /// Provides functionality to send key updates to the the connected client.
 ///
 /// This is a separate object since it is shared between the update thread and
-/// the handler threads.
+/// the handler threads.  Updates are queued and written to the client by a
+/// separate writer thread.
 pub struct Updater {
     pub addr: ClientAddr,
-    client:   Box<dyn Client>,
-    subs:     [Vec<String>; 2],
-    tsindex:  usize,
+    queue:    Arc<OutQueue>,
+    subs:     Vec<(String, Subscription)>,
     searcher: AhoCorasick,
+    patterns: Vec<(KeyPattern, Subscription)>,
+    limiter:  RateLimiter,
+    deadband: DeadbandFilter,
+    opts:     ProtoOpts,
+}
+
+/// A subscription of an updater.
+#[derive(Clone, Copy, Debug)]
+struct Subscription {
+    with_ts: bool,
+    opts:    SubOptions,
+    /// Sequence number of the last update before the subscription was added.
+    since:   u64,
 }
 
 /// These objects are sent to the updater thread from the DB and handlers.
 pub enum UpdaterMsg {
     NewUpdater(Box<Updater>),
-    Update(UpdaterEntry, Option<ClientAddr>),
-    Subscription(ClientAddr, String, bool),
-    CancelSubscription(ClientAddr, String, bool),
+    Update(Arc<UpdaterEntry>, Option<ClientAddr>),
+    Subscription(ClientAddr, KeyPattern, bool, SubOptions),
+    Snapshot(ClientAddr, String),
+    Replay(ClientAddr, Vec<Arc<UpdaterEntry>>, String),
+    CancelSubscription(ClientAddr, MatchKind, String, bool),
+    SetOptions(ClientAddr, ProtoOpts),
     RemoveUpdater(ClientAddr),
 }
 
+/// Sends the replies to a single request back to the client.
+///
+/// This takes care of the protocol options negotiated by the client: value
+/// escaping, request tags and end markers.
+pub struct ReplyTo<'a> {
+    send_q: &'a Sender<String>,
+    opts:   ProtoOpts,
+    tag:    Option<&'a str>,
+}
+
 /// Handles incoming queries on a connected client and executes the corresponding
 /// database calls.
 pub struct Handler {
@@ -66,41 +96,215 @@ pub struct Handler {
     db:     ThreadsafeDB,
     upd_q:  Sender<UpdaterMsg>,
     send_q: Sender<String>,
+    opts:   ProtoOpts,
 }
 
 impl Updater {
-    pub fn new(client: Box<dyn Client>, addr: ClientAddr) -> Updater {
-        Updater { addr, client, subs: [vec![], vec![]], tsindex: 0,
-                  searcher: AhoCorasick::new(Vec::<String>::new()).unwrap() }
+    pub fn new(client: Box<dyn Client>, addr: ClientAddr, options: QueueOptions) -> Updater {
+        let queue = Arc::new(OutQueue::new(options));
+        let writer_queue = queue.clone();
+        thread::spawn(move || writer_queue.writer(&addr.to_string(), client));
+        Updater { addr, queue, subs: vec![],
+                  searcher: AhoCorasick::new(Vec::<String>::new()).unwrap(),
+                  patterns: vec![], limiter: RateLimiter::default(),
+                  deadband: DeadbandFilter::default(), opts: ProtoOpts::default() }
     }
 
-    /// Add a new subscription for this client.
-    pub fn add_subscription(&mut self, key: String, with_ts: bool) {
-        self.subs[with_ts as usize].push(key);
-        self.subs_updated();
+    /// Set the protocol options negotiated by this client.
+    pub fn set_options(&mut self, opts: ProtoOpts) {
+        self.opts = opts;
+    }
+
+    /// Add a new subscription for this client, after the update with the
+    /// given sequence number.
+    pub fn add_subscription(&mut self, pattern: KeyPattern, with_ts: bool, opts: SubOptions,
+                            since: u64) {
+        let sub = Subscription { with_ts, opts, since };
+        if pattern.kind() == MatchKind::Substring {
+            self.subs.push((pattern.pattern().into(), sub));
+            self.subs_updated();
+        } else {
+            self.patterns.push((pattern, sub));
+        }
     }
 
     /// Remove a subscription for this client.
-    pub fn remove_subscription(&mut self, key: String, with_ts: bool) {
-        self.subs[with_ts as usize].retain(|substr| substr != &key);
-        self.subs_updated();
+    pub fn remove_subscription(&mut self, kind: MatchKind, key: String, with_ts: bool) {
+        if kind == MatchKind::Substring {
+            self.subs.retain(|(substr, sub)| !(substr == &key && sub.with_ts == with_ts));
+            self.subs_updated();
+        } else {
+            self.patterns.retain(|(pattern, sub)| {
+                !(pattern.kind() == kind && pattern.pattern() == key && sub.with_ts == with_ts)
+            });
+        }
+        self.queue.retain_keys(|key| self.find_subscription(key).is_some());
     }
 
     /// Rebuild the Aho-Corasick automaton used to match keys.
     fn subs_updated(&mut self) {
-        self.tsindex = self.subs[0].len();
-        self.searcher = AhoCorasick::new(self.subs[0].iter().chain(&self.subs[1]).cloned()).unwrap();
+        self.searcher = AhoCorasick::new(self.subs.iter().map(|sub| &sub.0)).unwrap();
+    }
+
+    /// Find the subscription matching a key.
+    fn find_subscription(&self, key: &str) -> Option<Subscription> {
+        if let Some(m) = self.searcher.find(key) {
+            Some(self.subs[m.pattern().as_usize()].1)
+        } else {
+            self.patterns.iter().find(|p| p.0.is_match(key)).map(|p| p.1)
+        }
+    }
+
+    /// Find the subscription that an update from the replay log should be sent
+    /// for, unless a subscription already matched it when it was processed.
+    fn missed_subscription(&self, entry: &UpdaterEntry) -> Option<Subscription> {
+        let key = entry.key();
+        let matching = self.subs.iter().filter(|s| key.contains(&*s.0)).map(|s| s.1)
+            .chain(self.patterns.iter().filter(|p| p.0.is_match(key)).map(|p| p.1));
+        let mut found = None;
+        for sub in matching {
+            if sub.since < entry.seq() {
+                return None;
+            }
+            found.get_or_insert(sub);
+        }
+        found
+    }
+
+    /// Queue an update, followed by its sequence number if the client wants it.
+    fn send(&self, entry: &UpdaterEntry, with_ts: bool) {
+        let msg = entry.get_msg(with_ts, self.opts.escape);
+        if self.opts.seq {
+            self.queue.push(entry.key(), &format!("{}{}={}\n", msg, SEQ_KEY, entry.seq()));
+        } else {
+            self.queue.push(entry.key(), msg);
+        }
     }
 
     /// Update this client, if the key is matched by one of the subscriptions.
-    pub fn update(&self, entry: &mut UpdaterEntry) {
-        if let Some(m) = self.searcher.find(entry.key()) {
-            debug!("[{}] update: {:?} | {:?}", self.addr, entry, self.subs);
-            let _ = self.client.write(entry.get_msg(m.pattern().as_usize() >= self.tsindex).as_bytes());
+    pub fn update(&mut self, entry: &Arc<UpdaterEntry>, now: Instant) {
+        if let Some(sub) = self.find_subscription(entry.key()) {
+            debug!("[{}] update: {:?} | {:?} {:?}", self.addr, entry, self.subs, self.patterns);
+            self.deliver(entry, sub, now);
+        }
+    }
+
+    /// Send an update for a subscription, unless its deadband or rate limit
+    /// holds it back.
+    fn deliver(&mut self, entry: &Arc<UpdaterEntry>, sub: Subscription, now: Instant) {
+        let Subscription { with_ts, opts, .. } = sub;
+        if let Some(deadband) = opts.deadband {
+            if !self.deadband.admit(entry, deadband) {
+                return;
+            }
+        }
+        if let Some(interval) = opts.interval {
+            if !self.limiter.admit(entry, interval, now) {
+                return;
+            }
+        }
+        self.send(entry, with_ts);
+    }
+
+    /// Queue the lines of a subscription's snapshot.
+    pub fn snapshot(&self, lines: &str) {
+        self.queue.push_all(lines);
+    }
+
+    /// Queue the updates from the replay log that this client has missed,
+    /// followed by the reply line.
+    ///
+    /// They are filtered like live updates, so updates held back by a rate
+    /// limit are sent after the reply, when their interval is over.
+    pub fn replay(&mut self, entries: &[Arc<UpdaterEntry>], reply: &str, now: Instant) {
+        for entry in entries {
+            if let Some(sub) = self.missed_subscription(entry) {
+                self.deliver(entry, sub, now);
+            }
+        }
+        self.queue.push_all(reply);
+    }
+
+    /// Get the time when the next held-back update is due.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.limiter.next_due()
+    }
+
+    /// Send the held-back updates that are due, if still subscribed.
+    pub fn flush(&mut self, now: Instant) {
+        for entry in self.limiter.take_due(now) {
+            if let Some(sub) = self.find_subscription(entry.key()) {
+                self.send(&entry, sub.with_ts);
+            }
+        }
+    }
+}
+
+impl Drop for Updater {
+    /// Let the writer thread quit once the queued updates are sent.
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+impl<'a> ReplyTo<'a> {
+    pub fn new(send_q: &'a Sender<String>, opts: ProtoOpts, tag: Option<&'a str>) -> ReplyTo<'a> {
+        ReplyTo { send_q, opts, tag }
+    }
+
+    /// Format a message as a reply line, with the request tag if given.
+    pub fn format(&self, msg: &CacheMsg) -> String {
+        let line = msg.to_line(self.opts.escape);
+        match self.tag {
+            Some(tag) => format!("[{}]{}", tag, line),
+            None => line,
+        }
+    }
+
+    /// Send a single message.
+    pub fn send(&self, msg: &CacheMsg) {
+        let _ = self.send_q.send(self.format(msg));
+    }
+
+    /// Send one or more lines that have already been formatted.
+    pub fn send_formatted(&self, lines: String) {
+        let _ = self.send_q.send(lines);
+    }
+
+    /// Mark the end of a multi-line result, if the client wants to know.
+    pub fn end(&self, count: usize) {
+        if let Some(line) = self.format_end(count) {
+            self.send_formatted(line);
+        }
+    }
+
+    /// Format the end mark of a multi-line result, if the client wants it.
+    pub fn format_end(&self, count: usize) -> Option<String> {
+        if self.opts.pipeline {
+            Some(self.format(&End { count }))
+        } else {
+            None
+        }
+    }
+
+    /// Report a rejected request, if the client enabled error replies.
+    pub fn error(&self, reason: &str) {
+        if self.opts.errors {
+            self.send(&Error { reason });
         }
     }
 }
 
+/// Split off the `[tag]` prefix of a request line, if present.
+fn split_tag(line: &str) -> (Option<&str>, &str) {
+    if let Some(rest) = line.strip_prefix('[') {
+        if let Some(i) = memchr(b']', rest.as_bytes()) {
+            return (Some(&rest[..i]), &rest[i+1..]);
+        }
+    }
+    (None, line)
+}
+
 impl Handler {
     pub fn new(client: Box<dyn Client>, upd_q: Sender<UpdaterMsg>, db: ThreadsafeDB) -> Handler {
         // spawn a thread that handles sending back replies to the socket
@@ -112,6 +316,7 @@ impl Handler {
             name:   client.get_addr().to_string(),
             addr:   client.get_addr(),
             send_q: w_msgs,
+            opts:   ProtoOpts::default(),
             client,
             db,
             upd_q,
@@ -130,42 +335,71 @@ impl Handler {
     }
 
     /// Handle a single cache message.
-    fn handle_msg(&self, msg: CacheMsg) {
-        // get a handle to the DB (since all but one of the message types require DB
-        // access, we do it here once)
-        let mut db = self.db.lock();
+    fn handle_msg(&self, msg: CacheMsg, reply: &ReplyTo) {
+        // the DB does its own locking, so that requests from different clients
+        // can be processed concurrently
+        let db = &self.db;
         match msg {
             // key updates
-            Tell { key, val, no_store } =>
-                if let Err(err) = db.tell(key, val, localtime(), 0., no_store, self.addr) {
+            Tell { key, val, no_store } => {
+                let val = self.opts.decode(val);
+                if let Err(err) = db.tell(key, &val, localtime(), 0., no_store, self.addr) {
                     warn!("could not write key {} to db: {}", key, err);
-                },
-            TellTS { time, ttl, key, val, no_store } =>
-                if let Err(err) = db.tell(key, val, time, ttl, no_store, self.addr) {
+                    reply.error(&format!("could not store key {}: {}", key, err));
+                }
+            },
+            TellTS { time, ttl, key, val, no_store } => {
+                let val = self.opts.decode(val);
+                if let Err(err) = db.tell(key, &val, time, ttl, no_store, self.addr) {
                     warn!("could not write key {} to db: {}", key, err);
-                },
+                    reply.error(&format!("could not store key {}: {}", key, err));
+                }
+            },
             // key inquiries
             Ask { key, with_ts } =>
-                db.ask(key, with_ts, &self.send_q),
-            AskWild { key, with_ts } =>
-                db.ask_wc(key, with_ts, &self.send_q),
-            AskHist { key, from, delta } =>
-                db.ask_hist(key, from, delta, &self.send_q),
+                db.ask(key, with_ts, reply),
+            AskWild { key, kind, with_ts } => match KeyPattern::new(kind, key) {
+                Ok(pattern) => db.ask_wc(&pattern, with_ts, reply),
+                Err(err) => reply.error(&format!("invalid pattern {:?}: {}", key, err)),
+            },
+            AskHist { delta, .. } if delta < 0. =>
+                reply.error("negative history delta"),
+            AskHist { key, from, delta, opts } => match HistOptions::parse(opts) {
+                Ok(opts) => db.ask_hist(key, from, delta, opts, reply),
+                Err(err) => reply.error(&err),
+            },
             // locking
+            // legacy clients still get the lock reply they are waiting for
+            Lock { client: "", .. } | Unlock { client: "", .. } if self.opts.errors =>
+                reply.error("lock request without client name"),
+            LockRes { .. } =>
+                reply.error("lock request must start with + or -"),
             Lock { key, client, time, ttl } =>
-                db.lock(true, key, client, time, ttl, &self.send_q),
+                db.lock(true, key, client, time, ttl, reply),
             Unlock { key, client } =>
-                db.lock(false, key, client, 0., 0., &self.send_q),
+                db.lock(false, key, client, 0., 0., reply),
             // meta messages
             Rewrite { new_prefix, old_prefix } =>
                 db.rewrite(new_prefix, old_prefix),
-            Subscribe { key, with_ts } => {
-                let _ = self.upd_q.send(
-                    UpdaterMsg::Subscription(self.addr, key.into(), with_ts));
+            Subscribe { key, kind, with_ts, opts } => match SubOptions::parse(opts) {
+                Ok(opts) => match KeyPattern::new(kind, key) {
+                    Ok(pattern) if opts.snapshot =>
+                        db.subscribe_with_snapshot(self.addr, pattern, with_ts, opts, reply),
+                    Ok(pattern) => {
+                        let _ = self.upd_q.send(
+                            UpdaterMsg::Subscription(self.addr, pattern, with_ts, opts));
+                    },
+                    Err(err) => reply.error(&format!("invalid pattern {:?}: {}", key, err)),
+                },
+                Err(err) => reply.error(&err),
+            },
+            Resume { seq } => match seq.parse() {
+                Ok(seq) => db.resume(self.addr, seq, reply),
+                Err(_) => reply.error(&format!("invalid sequence number {:?}", seq)),
             },
-            Unsub { key, with_ts } => {
+            Unsub { key, kind, with_ts } => {
                 let _ = self.upd_q.send(
-                    UpdaterMsg::CancelSubscription(self.addr, key.into(), with_ts));
+                    UpdaterMsg::CancelSubscription(self.addr, kind, key.into(), with_ts));
             },
             // we ignore TellOlds
             _ => (),
@@ -173,20 +407,32 @@ impl Handler {
     }
 
     /// Process a single line (message).
-    fn process(&self, line: &str) -> bool {
+    fn process(&mut self, line: &str) -> bool {
+        let (tag, line) = if self.opts.pipeline { split_tag(line) } else { (None, line) };
         match CacheMsg::parse(line) {
             Some(Quit) => {
                 // an empty line closes the connection
                 false
             }
+            Some(Options { options }) => {
+                // options change how all following messages are handled
+                debug!("[{}] setting options {:?}", self.name, options);
+                self.opts = ProtoOpts::parse(options);
+                let _ = self.upd_q.send(UpdaterMsg::SetOptions(self.addr, self.opts));
+                ReplyTo::new(&self.send_q, self.opts, tag)
+                    .send(&Options { options: &self.opts.to_string() });
+                true
+            }
             Some(msg) => {
                 debug!("[{}] processing {:?} => {:?}", self.name, line, msg);
-                self.handle_msg(msg);
+                self.handle_msg(msg, &ReplyTo::new(&self.send_q, self.opts, tag));
                 true
             }
             None => {
                 // not a valid cache protocol line => ignore it
                 warn!("[{}] strange line: {:?}", self.name, line);
+                ReplyTo::new(&self.send_q, self.opts, tag)
+                    .error(&format!("malformed line {:?}", line));
                 true
             }
         }
@@ -227,3 +473,54 @@ impl Handler {
         self.client.close();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+    use crossbeam_channel::{unbounded, Receiver};
+    use crate::database::DB;
+    use crate::message::ProtoOpts;
+    use crate::server::{Client, ClientAddr};
+    use crate::store_flat;
+    use super::Handler;
+
+    /// A client that is never read from or written to.
+    struct NoClient(ClientAddr);
+
+    impl Client for NoClient {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> { Ok(0) }
+        fn write(&self, _: &[u8]) -> io::Result<()> { Ok(()) }
+        fn try_clone(&self) -> io::Result<Box<dyn Client>> { Ok(Box::new(NoClient(self.0))) }
+        fn close(&mut self) {}
+        fn get_addr(&self) -> ClientAddr { self.0 }
+    }
+
+    fn new_handler(errors: bool) -> (Handler, Receiver<String>) {
+        let addr = "127.0.0.1:14869".parse().unwrap();
+        let (upd_q, _) = unbounded();
+        let (send_q, replies) = unbounded();
+        // lock requests never touch the store
+        let store = store_flat::Store::new(std::env::temp_dir().join("cache-rs-unused"), false, None);
+        let db = DB::new(Box::new(store), upd_q.clone());
+        (Handler { name: "test".into(), client: Box::new(NoClient(addr)), addr,
+                   db: Arc::new(db), upd_q, send_q,
+                   opts: ProtoOpts { errors, ..ProtoOpts::default() } }, replies)
+    }
+
+    #[test]
+    fn lock_without_client() {
+        // with error replies, the request is rejected and changes nothing
+        let (mut handler, replies) = new_handler(true);
+        handler.process("lock$+");
+        handler.process("lock$+other");
+        assert_eq!(replies.try_iter().collect::<Vec<_>>(),
+                   ["_error=lock request without client name\n", "lock$\n"]);
+
+        // legacy clients get the lock reply, as before
+        let (mut handler, replies) = new_handler(false);
+        handler.process("lock$+");
+        handler.process("lock$-");
+        assert_eq!(replies.try_iter().collect::<Vec<_>>(), ["lock$\n", "lock$\n"]);
+    }
+}
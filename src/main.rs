@@ -25,14 +25,26 @@
 mod entry;
 mod database;
 mod store_flat;
+mod store_log;
+mod store_memory;
+mod store_tee;
 #[cfg(feature = "postgres")]
 mod store_pgsql;
+#[cfg(feature = "sqlite")]
+mod store_sqlite;
 mod handler;
 mod message;
 mod server;
+mod migrate;
+mod retention;
+mod aggregate;
+mod outqueue;
+mod subscription;
+#[cfg(test)]
+mod testutil;
 
 use log::{info, error};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use signal_hook::iterator::Signals;
 
 #[derive(Parser)]
@@ -50,16 +62,52 @@ struct Options {
     verbose: bool,
     #[clap(long="clear", help="Clear the database on startup?")]
     clear: bool,
+    #[clap(long="history-index", help="Index flat-file history by time?")]
+    history_index: bool,
+    #[clap(long="compress-after", value_name="DAYS",
+           help="Compress flat-file store days that are DAYS days old")]
+    compress_after: Option<u32>,
+    #[clap(long="retain", value_name="RULE",
+           help="History retention rule [CATEGORY=]DAYS[,daily[=DAYS]] (repeatable)")]
+    retain: Vec<String>,
+    #[clap(long="mirror", help="Further store path or URI to write to (repeatable)")]
+    mirror: Vec<String>,
+    #[clap(long="client-queue", value_name="LEN", default_value="10000",
+           help="Maximum number of queued updates per client")]
+    client_queue: usize,
+    #[clap(long="overflow", value_name="POLICY", default_value="coalesce",
+           help="What to do with a full client queue: drop-client, drop-oldest or coalesce")]
+    overflow: outqueue::Overflow,
     #[clap(short='d', help="Daemonize?")]
     daemonize: bool,
     #[clap(long="user", help="User name for daemon")]
     user: Option<String>,
     #[clap(long="group", help="Group name for daemon")]
     group: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
     #[clap(hide=true)]
     _dummy: Option<String>,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Copy all entries from one store to another
+    Migrate {
+        #[clap(long="from", help="Store path or URI to read")]
+        from: String,
+        #[clap(long="to", help="Store path or URI to write (is cleared first)")]
+        to: String,
+    },
+    /// Check a flat-file store for problems
+    Fsck {
+        #[clap(long="store", help="Store path to check")]
+        store: String,
+        #[clap(long="repair", help="Repair problems where possible?")]
+        repair: bool,
+    },
+}
+
 fn main() {
     let args = Options::parse();
     let log_path = mlzutil::fs::abspath(args.log_path);
@@ -85,23 +133,72 @@ fn main() {
         }) {
         eprintln!("could not initialize logging: {}", err);
     }
-    let store_path = server::StorePath::parse(&args.store_path).unwrap_or_else(|err| {
-        error!("invalid store path: {}", err);
+    let parse = |path: &str| server::StorePath::parse(path).unwrap_or_else(|err| {
+        error!("invalid store path {}: {}", path, err);
         std::process::exit(1);
     });
+    match args.command {
+        Some(Command::Migrate { from, to }) => {
+            let result = migrate::migrate(parse(&from), parse(&to));
+            std::process::exit(if result.is_ok() { 0 } else { 1 });
+        }
+        Some(Command::Fsck { store, repair }) => {
+            let path = match parse(&store) {
+                server::StorePath::Fs(path) => path,
+                _ => {
+                    error!("fsck is only supported for flat-file stores");
+                    std::process::exit(1);
+                }
+            };
+            match store_flat::Fsck::new(path, repair).run() {
+                Ok(0) => std::process::exit(0),
+                Ok(_) => std::process::exit(1),
+                Err(err) => {
+                    error!("could not check store: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => ()
+    }
+    let store_path = parse(&args.store_path);
     if let Err(err) = mlzutil::fs::write_pidfile(&pid_path, "cache_rs") {
         error!("could not write PID file: {}", err);
     }
 
-    let server = server::Server::new(store_path, args.clear)
+    let mirrors = args.mirror.iter().map(|path| server::StorePath::parse(path))
+                                    .collect::<Result<_, _>>().unwrap_or_else(|err| {
+        error!("invalid mirror store path: {}", err);
+        std::process::exit(1);
+    });
+    let rules = args.retain.iter().map(|rule| retention::Rule::parse(rule))
+                                  .collect::<Result<_, _>>().unwrap_or_else(|err| {
+        error!("{}", err);
+        std::process::exit(1);
+    });
+    let store_options = server::StoreOptions {
+        history_index: args.history_index,
+        mirrors,
+        compress_after: args.compress_after,
+        retention: retention::Retention::new(rules),
+    };
+    let queue_options = outqueue::QueueOptions {
+        len: args.client_queue,
+        overflow: args.overflow,
+    };
+    let server = server::Server::new(store_path, store_options, queue_options, args.clear)
         .unwrap_or_else(|_| std::process::exit(1));
+    let db = server.db();
     info!("starting server on {}...", args.bind_addr);
     if let Err(err) = server.start(&args.bind_addr) {
         error!("could not initialize server: {}", err);
     }
 
     // wait for a signal to finish
-    Signals::new(&[libc::SIGINT, libc::SIGTERM]).unwrap().wait();
+    Signals::new([libc::SIGINT, libc::SIGTERM]).unwrap().wait();
     info!("quitting...");
+    if let Err(err) = db.shutdown() {
+        error!("could not shut down store: {}", err);
+    }
     mlzutil::fs::remove_pidfile(pid_path, "cache_rs");
 }
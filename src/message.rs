@@ -23,22 +23,224 @@
 //! This module contains the definition of a protocol message, along with tools
 //! to parse and string-format it.
 
-use regex::Regex;
-use once_cell::sync::Lazy;
+use std::borrow::Cow;
+use std::fmt::{self, Write};
+use memchr::{memchr, memchr2};
 use mlzutil::time::localtime;
 
-static MSG_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(?x)
-    ^ (?:
-      \s* (?P<time>\d+\.?\d*)?                 # timestamp
-      \s* (?P<ttlop>[+-]?)                     # ttl operator
-      \s* (?P<ttl>\d+\.?\d*(?:[eE][+-]?\d+)?)? # ttl
-      \s* (?P<tsop>@)                          # timestamp mark
-    )?
-    \s* (?P<key>[^=!?:*$]*?)                   # key
-    \s* (?P<op>[=!?:|*$~])                     # operator
-    \s* (?P<value>[^\r\n]*?)                   # value
-    \s* $
-    "#).unwrap());
+/// Key that clients send to enable protocol options for their connection.
+pub const OPTIONS_KEY: &str = "_options";
+/// Key used by the server to report errors, if the client enabled them.
+pub const ERROR_KEY: &str = "_error";
+/// Key used by the server to mark the end of a multi-line result.
+pub const END_KEY: &str = "_end";
+/// Key used by the server to send the sequence number of an update.
+pub const SEQ_KEY: &str = "_seq";
+/// Key that clients send to get the updates after a sequence number, and that
+/// the server replies with.
+pub const RESUME_KEY: &str = "_resume";
+/// Key that selects a glob pattern, given as the value, in wildcard queries
+/// and subscriptions.
+pub const GLOB_KEY: &str = "_glob";
+/// Key that selects a regular expression, given as the value, in wildcard
+/// queries and subscriptions.
+pub const REGEX_KEY: &str = "_regex";
+/// Key that selects a glob pattern for a subscription with options, given as
+/// `[options]pattern` in the value.
+pub const GLOB_OPTS_KEY: &str = "_glob_opts";
+/// Key that selects a regular expression for a subscription with options,
+/// given as `[options]pattern` in the value.
+pub const REGEX_OPTS_KEY: &str = "_regex_opts";
+
+/// The different ways a key pattern can match keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchKind {
+    /// the pattern can occur anywhere in the key
+    Substring,
+    /// the pattern is a glob that must match the whole key
+    Glob,
+    /// the pattern is a regular expression that must match somewhere in the key
+    Regex,
+}
+
+impl MatchKind {
+    /// Determine the pattern and its kind from the key and value of a message.
+    fn from_parts<'a>(key: &'a str, val: &'a str) -> (&'a str, MatchKind) {
+        match key {
+            GLOB_KEY => (val, MatchKind::Glob),
+            REGEX_KEY => (val, MatchKind::Regex),
+            _ => (key, MatchKind::Substring),
+        }
+    }
+
+    /// Determine the pattern, its kind and the options from the key and value
+    /// of a subscription message.
+    ///
+    /// Options are only looked for where the value cannot be a pattern, so
+    /// that `_glob` and `_regex` patterns starting with `[` keep their meaning.
+    fn sub_parts<'a>(key: &'a str, val: &'a str) -> (&'a str, MatchKind, &'a str) {
+        match key {
+            GLOB_KEY => (val, MatchKind::Glob, ""),
+            REGEX_KEY => (val, MatchKind::Regex, ""),
+            _ => {
+                let (opts, val) = split_sub_options(val);
+                match key {
+                    GLOB_OPTS_KEY => (val, MatchKind::Glob, opts),
+                    REGEX_OPTS_KEY => (val, MatchKind::Regex, opts),
+                    _ => (key, MatchKind::Substring, opts),
+                }
+            }
+        }
+    }
+
+    /// Format a pattern message with the given operator and options.
+    fn to_line(self, pattern: &str, op: char, opts: &str, with_ts: bool) -> String {
+        let ts = if with_ts { "@" } else { "" };
+        if opts.is_empty() {
+            return match self {
+                MatchKind::Substring => format!("{}{}{}\n", ts, pattern, op),
+                MatchKind::Glob => format!("{}{}{}{}\n", ts, GLOB_KEY, op, pattern),
+                MatchKind::Regex => format!("{}{}{}{}\n", ts, REGEX_KEY, op, pattern),
+            };
+        }
+        match self {
+            MatchKind::Substring => format!("{}{}{}[{}]\n", ts, pattern, op, opts),
+            MatchKind::Glob => format!("{}{}{}[{}]{}\n", ts, GLOB_OPTS_KEY, op, opts, pattern),
+            MatchKind::Regex => format!("{}{}{}[{}]{}\n", ts, REGEX_OPTS_KEY, op, opts, pattern),
+        }
+    }
+}
+
+/// Split off the `[options]` prefix of a subscription's value, if present.
+fn split_sub_options(val: &str) -> (&str, &str) {
+    if let Some(rest) = val.strip_prefix('[') {
+        if let Some(i) = memchr(b']', rest.as_bytes()) {
+            return (&rest[..i], rest[i+1..].trim_start());
+        }
+    }
+    ("", val)
+}
+
+/// Protocol options that a client can enable for its connection.
+///
+/// They are requested with a `_options=opt1,opt2` line, which replaces the
+/// currently enabled set; the server replies with the options it accepted.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProtoOpts {
+    /// Values are sent and received in escaped form (see `escape`).
+    pub escape: bool,
+    /// Rejected requests are answered with an error message.
+    pub errors: bool,
+    /// Requests can be tagged with a `[tag]` prefix, which is repeated on
+    /// every reply line, and multi-line results are followed by an end marker.
+    pub pipeline: bool,
+    /// Updates are followed by their sequence number.
+    pub seq: bool,
+}
+
+impl ProtoOpts {
+    /// Parse a comma-separated list of options, ignoring unknown ones.
+    pub fn parse(list: &str) -> ProtoOpts {
+        let mut opts = ProtoOpts::default();
+        for opt in list.split(',') {
+            match opt.trim() {
+                "escape" => opts.escape = true,
+                "errors" => opts.errors = true,
+                "pipeline" => opts.pipeline = true,
+                "seq" => opts.seq = true,
+                _ => (),
+            }
+        }
+        opts
+    }
+
+    /// Decode a value received from the client.
+    pub fn decode<'a>(&self, val: &'a str) -> Cow<'a, str> {
+        if self.escape { unescape(val) } else { Cow::Borrowed(val) }
+    }
+}
+
+impl fmt::Display for ProtoOpts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let enabled = [("escape", self.escape), ("errors", self.errors),
+                       ("pipeline", self.pipeline), ("seq", self.seq)];
+        let names = enabled.iter().filter(|opt| opt.1).map(|opt| opt.0).collect::<Vec<_>>();
+        f.write_str(&names.join(","))
+    }
+}
+
+/// Escape a value so that it contains no control characters.
+///
+/// Backslashes are doubled, newline, carriage return and tab become `\n`, `\r`
+/// and `\t`, and all other ASCII control characters become `\xHH`.
+pub fn escape(val: &str) -> Cow<'_, str> {
+    if !val.bytes().any(|b| b == b'\\' || b.is_ascii_control()) {
+        return Cow::Borrowed(val);
+    }
+    let mut res = String::with_capacity(val.len() + 8);
+    for ch in val.chars() {
+        match ch {
+            '\\' => res.push_str("\\\\"),
+            '\n'  => res.push_str("\\n"),
+            '\r'  => res.push_str("\\r"),
+            '\t'  => res.push_str("\\t"),
+            ch if ch.is_ascii_control() => { let _ = write!(res, "\\x{:02x}", ch as u8); }
+            ch => res.push(ch),
+        }
+    }
+    Cow::Owned(res)
+}
+
+/// Undo the escaping done by `escape`.
+///
+/// Backslashes that do not start a known escape are kept as they are.  `\x00`
+/// is not decoded, since NUL characters cannot be stored everywhere.
+pub fn unescape(val: &str) -> Cow<'_, str> {
+    if memchr(b'\\', val.as_bytes()).is_none() {
+        return Cow::Borrowed(val);
+    }
+    let mut res = String::with_capacity(val.len());
+    let mut rest = val;
+    while let Some(i) = memchr(b'\\', rest.as_bytes()) {
+        res.push_str(&rest[..i]);
+        let (decoded, len) = match rest.as_bytes().get(i+1) {
+            Some(b'\\') => ('\\', 2),
+            Some(b'n')  => ('\n', 2),
+            Some(b'r')  => ('\r', 2),
+            Some(b't')  => ('\t', 2),
+            Some(b'x')  => match rest.get(i+2..i+4) {
+                Some(hex) if hex.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                    match u8::from_str_radix(hex, 16) {
+                        Ok(b) if b != 0 && b.is_ascii_control() => (b as char, 4),
+                        _ => ('\\', 1),
+                    }
+                }
+                _ => ('\\', 1),
+            },
+            _ => ('\\', 1),
+        };
+        res.push(decoded);
+        rest = &rest[i+len..];
+    }
+    res.push_str(rest);
+    Cow::Owned(res)
+}
+
+/// Formats a value for a protocol line.
+///
+/// Values are escaped if the client requested it, or if they contain a line
+/// break that would otherwise split the message.
+struct Value<'a>(&'a str, bool);
+
+impl<'a> fmt::Display for Value<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.1 || memchr2(b'\r', b'\n', self.0.as_bytes()).is_some() {
+            f.write_str(&escape(self.0))
+        } else {
+            f.write_str(self.0)
+        }
+    }
+}
 
 /// An algebraic data type that represents any message (line) that can be sent
 /// over the network in the cache protocol.
@@ -60,13 +262,13 @@ pub enum CacheMsg<'a> {
     /// query for a single key
     Ask       { key: &'a str, with_ts: bool },
     /// query for multiple keys with a wildcard
-    AskWild   { key: &'a str, with_ts: bool },
-    /// query for history of a single key
-    AskHist   { key: &'a str, from: f64, delta: f64 },
-    /// subscription to a key substring
-    Subscribe { key: &'a str, with_ts: bool },
+    AskWild   { key: &'a str, kind: MatchKind, with_ts: bool },
+    /// query for history of a single key, with options (limit, order, aggregation)
+    AskHist   { key: &'a str, from: f64, delta: f64, opts: &'a str },
+    /// subscription to a key substring, with options (e.g. rate limit)
+    Subscribe { key: &'a str, kind: MatchKind, with_ts: bool, opts: &'a str },
     /// unsubscription
-    Unsub     { key: &'a str, with_ts: bool },
+    Unsub     { key: &'a str, kind: MatchKind, with_ts: bool },
     /// lock request
     Lock      { key: &'a str, client: &'a str, time: f64, ttl: f64 },
     /// unlock request
@@ -75,6 +277,14 @@ pub enum CacheMsg<'a> {
     LockRes   { key: &'a str, client: &'a str },
     /// set or delete of a prefix rewrite
     Rewrite   { new_prefix: &'a str, old_prefix: &'a str },
+    /// request or confirmation of protocol options
+    Options   { options: &'a str },
+    /// request for the updates after a sequence number, or its result
+    Resume    { seq: &'a str },
+    /// error reply for a rejected request
+    Error     { reason: &'a str },
+    /// end of a multi-line result with the given number of lines
+    End       { count: usize },
 }
 
 use self::CacheMsg::*;
@@ -82,25 +292,27 @@ use self::CacheMsg::*;
 impl<'a> CacheMsg<'a> {
     /// Parse a String containing a cache message.
     ///
-    /// This matches a regular expression, and then creates a `CacheMsg` if successful.
-    pub fn parse(line: &str) -> Option<CacheMsg> {
-        if let Some(captures) = MSG_RE.captures(line) {
+    /// This splits the line into its parts, and then creates a `CacheMsg` if
+    /// successful.
+    pub fn parse(line: &str) -> Option<CacheMsg<'_>> {
+        if let Some(RawMsg { prefix, key, op, val }) = split_line(line) {
             let t1;
             let mut dt = 0.;
-            let has_tsop = captures.name("tsop").is_some();
-            if has_tsop {
-                t1 = captures.name("time").and_then(|m| m.as_str().parse().ok()).unwrap_or_else(localtime);
-                dt = captures.name("ttl").and_then(|m| m.as_str().parse().ok()).unwrap_or(0.);
-                if captures.name("ttlop").map_or("", |m| m.as_str()) == "-" {
+            let has_tsop = prefix.is_some();
+            if let Some(prefix) = prefix {
+                t1 = prefix.time.and_then(|s| s.parse().ok()).unwrap_or_else(localtime);
+                dt = prefix.ttl.and_then(|s| s.parse().ok()).unwrap_or(0.);
+                if prefix.ttlop == "-" {
                     dt -= t1;
                 }
             } else {
                 t1 = localtime();
             }
-            let key = captures.name("key").expect("no key in match?!").as_str();
-            let val = captures.name("value").map_or("", |m| m.as_str());
-            match captures.name("op").expect("no op in match?!").as_str() {
-                "=" => {
+            match op {
+                b'=' if key == OPTIONS_KEY => Some(Options { options: val }),
+                b'=' if key == ERROR_KEY => Some(Error { reason: val }),
+                b'=' if key == RESUME_KEY => Some(Resume { seq: val }),
+                b'=' => {
                     // handle the "no store" flag, a "#" after the key name
                     let no_store = key.ends_with('#');
                     let key = if no_store { &key[0..key.len() - 1] } else { key };
@@ -109,32 +321,40 @@ impl<'a> CacheMsg<'a> {
                     } else {
                         Some(Tell { key, val, no_store })
                     }},
-                "!" =>
+                b'!' =>
                     if has_tsop {
                         Some(TellOldTS { key, val, time: t1, ttl: dt })
                     } else {
                         Some(TellOld { key, val })
                     },
-                "?" =>
+                b'?' =>
                     if has_tsop && dt != 0. {
-                        Some(AskHist { key, from: t1, delta: dt })
+                        Some(AskHist { key, from: t1, delta: dt, opts: val })
                     } else {
                         Some(Ask { key, with_ts: has_tsop })
                     },
-                "*" =>  Some(AskWild { key, with_ts: has_tsop }),
-                ":" =>  Some(Subscribe { key, with_ts: has_tsop }),
-                "|" =>  Some(Unsub { key, with_ts: has_tsop }),
-                "$" => {
-                    let client = &val[1..];
-                    if &val[0..1] == "+" {
+                b'*' => {
+                    let (key, kind) = MatchKind::from_parts(key, val);
+                    Some(AskWild { key, kind, with_ts: has_tsop })
+                },
+                b':' => {
+                    let (key, kind, opts) = MatchKind::sub_parts(key, val);
+                    Some(Subscribe { key, kind, with_ts: has_tsop, opts })
+                },
+                b'|' => {
+                    let (key, kind) = MatchKind::from_parts(key, val);
+                    Some(Unsub { key, kind, with_ts: has_tsop })
+                },
+                b'$' =>
+                    if let Some(client) = val.strip_prefix('+') {
                         Some(Lock { key, client, time: t1, ttl: dt })
-                    } else if &val[0..1] == "-" {
+                    } else if let Some(client) = val.strip_prefix('-') {
                         Some(Unlock { key, client })
                     } else {
                         Some(LockRes { key, client: val })
-                    }},
-                "~" =>  Some(Rewrite { new_prefix: key, old_prefix: val }),
-                _   =>  None,
+                    },
+                b'~' => Some(Rewrite { new_prefix: key, old_prefix: val }),
+                _    => None,
             }
         } else if line.trim() == "" {
             Some(Quit)
@@ -144,52 +364,205 @@ impl<'a> CacheMsg<'a> {
     }
 }
 
-/// "Serialize" a `CacheMsg` back to a String.
+/// The optional `[time] [+-] [ttl] @` prefix of a protocol line.
+#[derive(Debug, PartialEq)]
+struct Prefix<'a> {
+    time:  Option<&'a str>,
+    ttlop: &'a str,
+    ttl:   Option<&'a str>,
+}
+
+/// A protocol line split into its parts, but not yet interpreted.
 ///
-/// Not all messages are actually used for stringification, but this is also
-/// nice for debugging purposes.
-impl<'a> ToString for CacheMsg<'a> {
-    fn to_string(&self) -> String {
+/// The grammar is the same as the regular expression that was used before,
+/// namely (with `\s` meaning any Unicode whitespace):
+///
+/// ```text
+/// ^ (?: \s* (?P<time>\d+\.?\d*)?
+///       \s* (?P<ttlop>[+-]?)
+///       \s* (?P<ttl>\d+\.?\d*(?:[eE][+-]?\d+)?)?
+///       \s* (?P<tsop>@) )?
+/// \s* (?P<key>[^=!?:*$]*?) \s* (?P<op>[=!?:|*$~]) \s* (?P<value>[^\r\n]*?) \s* $
+/// ```
+///
+/// The only difference is that `\d` is restricted to ASCII digits; other
+/// Unicode digits could never be parsed as a float anyway.
+#[derive(Debug, PartialEq)]
+struct RawMsg<'a> {
+    prefix: Option<Prefix<'a>>,
+    key:    &'a str,
+    op:     u8,
+    val:    &'a str,
+}
+
+/// Return the index of the first non-whitespace character at or after `i`.
+#[inline]
+fn skip_ws(line: &str, mut i: usize) -> usize {
+    let bytes = line.as_bytes();
+    while i < bytes.len() {
+        if bytes[i] < 0x80 {
+            if !matches!(bytes[i], b' ' | b'\t'..=b'\r') {
+                break;
+            }
+            i += 1;
+        } else {
+            match line[i..].chars().next() {
+                Some(ch) if ch.is_whitespace() => i += ch.len_utf8(),
+                _ => break,
+            }
+        }
+    }
+    i
+}
+
+/// Return the index of the first non-digit byte at or after `i`.
+#[inline]
+fn skip_digits(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() && bytes[i].is_ascii_digit() {
+        i += 1;
+    }
+    i
+}
+
+/// Return the end of the longest match of `\d+\.?\d*` at `i`, or `i` if
+/// there is no match.
+///
+/// Every nonempty prefix of this match is also a match, and the regex engine
+/// tries them in order of decreasing length.
+#[inline]
+fn number_end(bytes: &[u8], i: usize) -> usize {
+    let end = skip_digits(bytes, i);
+    if end > i && end < bytes.len() && bytes[end] == b'.' {
+        skip_digits(bytes, end + 1)
+    } else {
+        end
+    }
+}
+
+/// Return the end of the exponent part `[eE][+-]?\d+` at `i`, if present.
+#[inline]
+fn exponent_end(bytes: &[u8], i: usize) -> Option<usize> {
+    if i >= bytes.len() || !matches!(bytes[i], b'e' | b'E') {
+        return None;
+    }
+    let mut j = i + 1;
+    if j < bytes.len() && matches!(bytes[j], b'+' | b'-') {
+        j += 1;
+    }
+    let end = skip_digits(bytes, j);
+    if end > j { Some(end) } else { None }
+}
+
+/// Match `\s* [+-]? \s* (ttl)? \s*` starting at `i`, which has to end exactly
+/// at the `@` at index `at`.
+fn split_ttl(line: &str, i: usize, at: usize) -> Option<(&str, Option<&str>)> {
+    let bytes = line.as_bytes();
+    let mut i = skip_ws(line, i);
+    let mut ttlop = "";
+    if matches!(bytes[i], b'+' | b'-') {
+        ttlop = &line[i..i+1];
+        i = skip_ws(line, i + 1);
+    }
+    let mant_end = number_end(bytes, i);
+    if mant_end > i {
+        // the exponent is greedy, so the longest candidate is tried first
+        if let Some(exp_end) = exponent_end(bytes, mant_end) {
+            for end in (mant_end + 2..=exp_end).rev() {
+                if bytes[end - 1].is_ascii_digit() && skip_ws(line, end) == at {
+                    return Some((ttlop, Some(&line[i..end])));
+                }
+            }
+        }
+        for end in (i + 1..=mant_end).rev() {
+            if skip_ws(line, end) == at {
+                return Some((ttlop, Some(&line[i..end])));
+            }
+        }
+    }
+    if i == at {
+        return Some((ttlop, None));
+    }
+    None
+}
+
+/// Match the optional timestamp prefix.  If present, it ends at the first `@`
+/// in the line; the returned index is just after the `@`.
+fn split_prefix(line: &str) -> Option<(Prefix<'_>, usize)> {
+    let bytes = line.as_bytes();
+    let at = memchr(b'@', bytes)?;
+    let i = skip_ws(line, 0);
+    // the time is greedy, so try candidates in order of decreasing length,
+    // and without any time last
+    for end in (i + 1..=number_end(bytes, i)).rev() {
+        if let Some((ttlop, ttl)) = split_ttl(line, end, at) {
+            return Some((Prefix { time: Some(&line[i..end]), ttlop, ttl }, at + 1));
+        }
+    }
+    split_ttl(line, i, at).map(|(ttlop, ttl)| (Prefix { time: None, ttlop, ttl }, at + 1))
+}
+
+/// Split a protocol line into its parts, without any allocation.
+fn split_line(line: &str) -> Option<RawMsg<'_>> {
+    let bytes = line.as_bytes();
+    let (prefix, start) = match split_prefix(line) {
+        Some((prefix, start)) => (Some(prefix), start),
+        None => (None, 0),
+    };
+    // the key cannot contain any of "=!?:*$", but it can contain "|" and "~";
+    // so the first operator whose value part is acceptable is the one
+    let mut i = start;
+    while i < bytes.len() {
+        let op = bytes[i];
+        if matches!(op, b'=' | b'!' | b'?' | b':' | b'*' | b'$' | b'|' | b'~') {
+            // the value cannot contain CR or LF except in trailing whitespace
+            let val = line[i+1..].trim();
+            if memchr2(b'\r', b'\n', val.as_bytes()).is_none() {
+                return Some(RawMsg { prefix, key: line[start..i].trim(), op, val });
+            }
+            if !matches!(op, b'|' | b'~') {
+                return None;
+            }
+        }
+        i += 1;
+    }
+    None
+}
+
+impl<'a> CacheMsg<'a> {
+    /// "Serialize" a `CacheMsg` back to a String, escaping values if requested.
+    ///
+    /// Not all messages are actually used for stringification, but this is also
+    /// nice for debugging purposes.
+    pub fn to_line(&self, escape: bool) -> String {
+        let value = |val| Value(val, escape);
         match *self {
             Quit => String::from("\n"),
             Tell { key, val, no_store } =>
-                format!("{}{}={}\n", key, if no_store { "#" } else { "" }, val),
+                format!("{}{}={}\n", key, if no_store { "#" } else { "" }, value(val)),
             TellTS { key, val, time, ttl, no_store } =>
                 if ttl > 0. {
-                    format!("{}+{}@{}{}={}\n", time, ttl, key, if no_store { "#" } else { "" }, val)
+                    format!("{}+{}@{}{}={}\n", time, ttl, key, if no_store { "#" } else { "" }, value(val))
                 } else {
-                    format!("{}@{}{}={}\n", time, key, if no_store { "#" } else { "" }, val)
+                    format!("{}@{}{}={}\n", time, key, if no_store { "#" } else { "" }, value(val))
                 },
             TellOld { key, val } =>
-                format!("{}!{}\n", key, val),
+                format!("{}!{}\n", key, value(val)),
             TellOldTS { key, val, time, ttl } =>
-                format!("{}+{}@{}!{}\n", time, ttl, key, val),
+                format!("{}+{}@{}!{}\n", time, ttl, key, value(val)),
             Ask { key, with_ts } =>
                 if with_ts {
                     format!("@{}?\n", key)
                 } else {
                     format!("{}?\n", key)
                 },
-            AskWild { key, with_ts } =>
-                if with_ts {
-                    format!("@{}*\n", key)
-                } else {
-                    format!("{}*\n", key)
-                },
-            AskHist { key, from, delta } =>
-                format!("{}+{}@{}?\n", from, delta, key),
-            Subscribe { key, with_ts } =>
-                if with_ts {
-                    format!("@{}:\n", key)
-                } else {
-                    format!("{}:\n", key)
-                },
-            Unsub { key, with_ts } =>
-                if with_ts {
-                    format!("@{}|\n", key)
-                } else {
-                    format!("{}|\n", key)
-                },
+            AskWild { key, kind, with_ts } =>
+                kind.to_line(key, '*', "", with_ts),
+            AskHist { key, from, delta, opts } =>
+                format!("{}+{}@{}?{}\n", from, delta, key, opts),
+            Subscribe { key, kind, with_ts, opts } =>
+                kind.to_line(key, ':', opts, with_ts),
+            Unsub { key, kind, with_ts } =>
+                kind.to_line(key, '|', "", with_ts),
             Lock { key, client, time, ttl } => {
                 format!("{}+{}@{}$+{}\n", time, ttl, key, client)},
             Unlock { key, client } => {
@@ -198,6 +571,164 @@ impl<'a> ToString for CacheMsg<'a> {
                 format!("{}${}\n", key, client)},
             Rewrite { new_prefix, old_prefix } =>
                 format!("{}~{}\n", new_prefix, old_prefix),
+            Options { options } =>
+                format!("{}={}\n", OPTIONS_KEY, options),
+            Resume { seq } =>
+                format!("{}={}\n", RESUME_KEY, seq),
+            Error { reason } =>
+                format!("{}={}\n", ERROR_KEY, value(reason)),
+            End { count } =>
+                format!("{}={}\n", END_KEY, count),
+        }
+    }
+}
+
+impl<'a> fmt::Display for CacheMsg<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_line(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+    use once_cell::sync::Lazy;
+    use super::{RawMsg, Prefix, CacheMsg, MatchKind, split_line, escape, unescape};
+
+    /// The regular expression that was used to parse lines before.
+    static MSG_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"(?x)
+        ^ (?:
+          \s* (?P<time>\d+\.?\d*)?                 # timestamp
+          \s* (?P<ttlop>[+-]?)                     # ttl operator
+          \s* (?P<ttl>\d+\.?\d*(?:[eE][+-]?\d+)?)? # ttl
+          \s* (?P<tsop>@)                          # timestamp mark
+        )?
+        \s* (?P<key>[^=!?:*$]*?)                   # key
+        \s* (?P<op>[=!?:|*$~])                     # operator
+        \s* (?P<value>[^\r\n]*?)                   # value
+        \s* $
+        "#).unwrap());
+
+    fn split_line_regex(line: &str) -> Option<RawMsg<'_>> {
+        let captures = MSG_RE.captures(line)?;
+        let prefix = captures.name("tsop").map(|_| Prefix {
+            time:  captures.name("time").map(|m| m.as_str()),
+            ttlop: captures.name("ttlop").map_or("", |m| m.as_str()),
+            ttl:   captures.name("ttl").map(|m| m.as_str()),
+        });
+        Some(RawMsg {
+            prefix,
+            key: captures.name("key").unwrap().as_str(),
+            op:  captures.name("op").unwrap().as_str().as_bytes()[0],
+            val: captures.name("value").unwrap().as_str(),
+        })
+    }
+
+    fn check(line: &str) {
+        assert_eq!(split_line(line), split_line_regex(line), "line: {:?}", line);
+    }
+
+    #[test]
+    fn known_lines() {
+        for line in [
+            "", "  ", "\r", "key=val", " key = val \r", "key#=val", "nicos/dev/value=1.5",
+            "123.5@key=val", "123.5+10@key=val", "123.5-200@key?", "+5@key=val",
+            "-1e3@key=", "1.5e3@key=x", "1.e5@key=x", "12 34@key=x", "1 + 2e+5 @ key!val",
+            "@key?", "@key*", "@key:", "@key|", "key*", "key:", "key|", "key~other",
+            "key$+client", "key$-client", "key$client", "a|b=c", "a~b|c", "a|b\rc~d",
+            "a=b\rc", "a=b\nc", "key=a@b", "x@key=val", "1@2@key=val", "1.2.3@k=v",
+            "\u{a0}1\u{2003}@\u{85}key\u{a0}=\u{a0}val\u{3000}", "k\u{e9}y=v\u{e9}l",
+        ] {
+            check(line);
+        }
+    }
+
+    #[test]
+    fn generated_lines() {
+        // pieces that can make up the timestamp prefix
+        const PREFIX_PIECES: &[&str] = &[
+            "0", "1", "42", "1.5", ".", "e", "E", "e+", "+", "-", " ", "\u{a0}", "@",
+        ];
+        const PIECES: &[&str] = &[
+            "0", "1", "5", "42", "1234567890.25", ".", "e", "E", "+", "-", "@",
+            " ", "  ", "\t", "\r", "\n", "\u{b}", "\u{a0}", "\u{2003}", "\u{85}",
+            "=", "!", "?", ":", "|", "*", "$", "~", "#",
+            "a", "key", "nicos/", "dev/value", "\u{e9}", "\u{1f600}", "x y",
+        ];
+        // simple xorshift generator, to get a reproducible corpus
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut line = String::new();
+        for _ in 0..200_000 {
+            line.clear();
+            for _ in 0..next() % 6 {
+                line.push_str(PREFIX_PIECES[(next() % PREFIX_PIECES.len() as u64) as usize]);
+            }
+            for _ in 0..next() % 10 {
+                line.push_str(PIECES[(next() % PIECES.len() as u64) as usize]);
+            }
+            check(&line);
+        }
+    }
+
+    #[test]
+    fn lock_messages() {
+        assert!(matches!(CacheMsg::parse("key$+me"), Some(CacheMsg::Lock { client: "me", .. })));
+        assert!(matches!(CacheMsg::parse("key$-me"), Some(CacheMsg::Unlock { client: "me", .. })));
+        assert!(matches!(CacheMsg::parse("key$"), Some(CacheMsg::LockRes { client: "", .. })));
+        assert!(matches!(CacheMsg::parse("key$\u{e9}"), Some(CacheMsg::LockRes { .. })));
+    }
+
+    #[test]
+    fn history_messages() {
+        assert!(matches!(CacheMsg::parse("10-20@key?"),
+                         Some(CacheMsg::AskHist { from, delta, opts: "", .. })
+                         if from == 10. && delta == 10.));
+        assert!(matches!(CacheMsg::parse("10+5@key?1,mean"),
+                         Some(CacheMsg::AskHist { opts: "1,mean", .. })));
+        assert!(matches!(CacheMsg::parse("@key?1,mean"), Some(CacheMsg::Ask { .. })));
+    }
+
+    #[test]
+    fn subscription_messages() {
+        assert!(matches!(CacheMsg::parse("key:"),
+                         Some(CacheMsg::Subscribe { key: "key", opts: "", .. })));
+        assert!(matches!(CacheMsg::parse("key:[rate=10]"),
+                         Some(CacheMsg::Subscribe { key: "key", opts: "rate=10", .. })));
+        assert!(matches!(CacheMsg::parse("_regex:[ab]/value"),
+                         Some(CacheMsg::Subscribe { key: "[ab]/value", opts: "",
+                                                    kind: MatchKind::Regex, .. })));
+        assert!(matches!(CacheMsg::parse("_glob:[nm]icos/*"),
+                         Some(CacheMsg::Subscribe { key: "[nm]icos/*", opts: "",
+                                                    kind: MatchKind::Glob, .. })));
+        assert!(matches!(CacheMsg::parse("_regex_opts:[rate=1][ab]/value"),
+                         Some(CacheMsg::Subscribe { key: "[ab]/value", opts: "rate=1",
+                                                    kind: MatchKind::Regex, .. })));
+        for line in ["@_glob_opts:[rate=10]nicos/*/value\n", "key:[rate=1]\n",
+                     "_regex:[ab]/value\n"] {
+            assert_eq!(CacheMsg::parse(line.trim_end()).unwrap().to_line(false), line);
+        }
+    }
+
+    #[test]
+    fn escaping() {
+        for val in ["", "plain", "back\\slash", "a\nb\r\n", "tab\there", "\x01\x7f\u{e9}",
+                    "\\n is not a newline", "trailing\\"] {
+            let escaped = escape(val);
+            assert!(!escaped.bytes().any(|b| b.is_ascii_control()));
+            assert_eq!(unescape(&escaped), val);
+            let line = CacheMsg::Tell { key: "k", val, no_store: false }.to_line(true);
+            match CacheMsg::parse(line.trim_end_matches('\n')) {
+                Some(CacheMsg::Tell { val: parsed, .. }) => assert_eq!(unescape(parsed), val),
+                other => panic!("unexpected parse result {:?}", other),
+            }
         }
+        // unknown escapes are kept as they are
+        assert_eq!(unescape("\\q\\x0g\\x00\\"), "\\q\\x0g\\x00\\");
     }
 }
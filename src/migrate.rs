@@ -0,0 +1,108 @@
+// -----------------------------------------------------------------------------
+// A Rust implementation of the NICOS cache server.
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// -----------------------------------------------------------------------------
+//
+//
+//! Migration of the full history between store backends.
+
+use log::{info, error};
+use hashbrown::HashMap;
+
+use crate::database::EntryMap;
+use crate::entry::split_key;
+use crate::server::{Server, StoreOptions, StorePath};
+
+/// Number of entries between progress reports.
+const PROGRESS_INTERVAL: usize = 100_000;
+
+/// Replay all entries of one store into another, which is cleared first.
+pub fn migrate(from: StorePath, to: StorePath) -> Result<(), ()> {
+    let mut from = Server::make_store(from, &StoreOptions::default())?;
+    let mut to = Server::make_store(to, &StoreOptions::default())?;
+    // the source is loaded as on startup, some stores need that for exporting
+    if let Err(e) = from.load_latest(&mut EntryMap::default()) {
+        error!("could not load source store: {}", e);
+        return Err(());
+    }
+    if let Err(e) = to.clear() {
+        error!("could not clear target store: {}", e);
+        return Err(());
+    }
+
+    // the latest entries are needed when the target rolls over
+    let mut latest = EntryMap::default();
+    let mut count = 0;
+    let result = from.export(&mut |key, entry| {
+        if to.needs_rollover(entry.time) {
+            to.rollover(entry.time, &[&latest])?;
+        }
+        to.import(key, entry)?;
+        let (catname, subkey) = split_key(key);
+        let map = latest.entry_ref(catname).or_insert_with(HashMap::default);
+        if map.get(subkey).map_or(true, |prev| prev.time <= entry.time) {
+            map.insert(subkey.into(), entry.clone());
+        }
+        count += 1;
+        if count % PROGRESS_INTERVAL == 0 {
+            info!("migrated {} entries...", count);
+        }
+        Ok(())
+    }).and_then(|_| to.shutdown());
+
+    match result {
+        Ok(()) => {
+            info!("migrated {} entries with {} keys", count,
+                  latest.values().map(|map| map.len()).sum::<usize>());
+            Ok(())
+        }
+        Err(e) => {
+            error!("migration failed after {} entries: {}", count, e);
+            Err(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("migrate");
+        let snapshot = |name| StorePath::Memory(Some(dir.join(name)), 100);
+        let lines = "a/b\t1\t+\tx\nc\t2\t-\ty\na/b\t3\t-\t-\na/b\t4\t+\tz\\tz\tesc\n";
+        fs::write(dir.join("src"), lines).unwrap();
+
+        migrate(snapshot("src"), StorePath::Fs(dir.join("flat"))).unwrap();
+        migrate(StorePath::Fs(dir.join("flat")),
+                StorePath::Uri(format!("log://{}", dir.join("log").display()))).unwrap();
+        migrate(StorePath::Uri(format!("log://{}", dir.join("log").display())),
+                snapshot("dst")).unwrap();
+
+        let mut result = fs::read_to_string(dir.join("dst")).unwrap().lines()
+                                                            .map(String::from).collect::<Vec<_>>();
+        result.sort();
+        assert_eq!(result, ["a/b\t1\t+\tx", "a/b\t3\t-\t-", "a/b\t4\t+\tz\\tz\tesc",
+                            "c\t2\t-\ty"]);
+    }
+}
@@ -0,0 +1,282 @@
+// -----------------------------------------------------------------------------
+// A Rust implementation of the NICOS cache server.
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// -----------------------------------------------------------------------------
+//
+//! Bounded outbound queues for the updates sent to subscribed clients.
+
+use std::fmt;
+use std::str::FromStr;
+use std::collections::VecDeque;
+use hashbrown::HashMap;
+use log::{info, warn};
+use parking_lot::{Condvar, Mutex};
+
+use crate::server::Client;
+
+/// What to do when the queue of a client is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Disconnect the client.
+    DropClient,
+    /// Drop the oldest queued single update.
+    DropOldest,
+    /// Replace a queued update of the same key with the new one, and queue
+    /// updates of other keys anyway.
+    Coalesce,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Overflow, String> {
+        match s {
+            "drop-client" => Ok(Overflow::DropClient),
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "coalesce" => Ok(Overflow::Coalesce),
+            _ => Err(format!("invalid overflow policy {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Overflow::DropClient => "drop-client",
+            Overflow::DropOldest => "drop-oldest",
+            Overflow::Coalesce => "coalesce",
+        })
+    }
+}
+
+/// Options for the outbound queues of clients.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueOptions {
+    /// Maximum number of queued updates.
+    pub len:      usize,
+    /// What to do when the queue is full.
+    pub overflow: Overflow,
+}
+
+#[derive(Default)]
+struct State {
+    /// Queued updates, with their keys (empty for several lines).
+    items:      VecDeque<(String, String)>,
+    /// Index of the last queued update of each key, for coalescing.
+    slots:      HashMap<String, usize>,
+    /// No further updates are accepted.
+    closed:     bool,
+    /// The client should be disconnected.
+    overflowed: bool,
+}
+
+/// Queue of updates for one client, which are written by a separate thread so
+/// that a slow client doesn't hold up the updater.
+pub struct OutQueue {
+    state:   Mutex<State>,
+    cond:    Condvar,
+    options: QueueOptions,
+}
+
+impl OutQueue {
+    pub fn new(options: QueueOptions) -> OutQueue {
+        OutQueue { state: Mutex::new(State::default()), cond: Condvar::new(), options }
+    }
+
+    /// Queue an update message for a key, applying the overflow policy if the
+    /// queue is full.
+    pub fn push(&self, key: &str, msg: &str) {
+        self.push_item(key, msg);
+    }
+
+    /// Queue several lines that belong together, e.g. a subscription's
+    /// snapshot.  They count as one update, and are never dropped or replaced.
+    pub fn push_all(&self, lines: &str) {
+        self.push_item("", lines);
+    }
+
+    /// Queue an item, with an empty key for several lines.  If the queue is
+    /// full and the overflow policy can't make room by dropping or replacing a
+    /// single update, the client is disconnected instead.
+    ///
+    /// When coalescing, a full queue keeps one update per key, so that it is
+    /// bounded by the number of subscribed keys.
+    fn push_item(&self, key: &str, msg: &str) {
+        let mut state = self.state.lock();
+        if state.closed {
+            return;
+        }
+        let coalesce = self.options.overflow == Overflow::Coalesce && !key.is_empty();
+        if state.items.len() >= self.options.len.max(1) {
+            let room = match self.options.overflow {
+                Overflow::DropClient => false,
+                Overflow::DropOldest => {
+                    match state.items.iter().position(|item| !item.0.is_empty()) {
+                        Some(index) => state.items.remove(index).is_some(),
+                        None => false,
+                    }
+                }
+                Overflow::Coalesce if coalesce => {
+                    if let Some(&index) = state.slots.get(key) {
+                        state.items[index].1 = msg.into();
+                        return;
+                    }
+                    true
+                }
+                Overflow::Coalesce => false,
+            };
+            if !room {
+                state.items.clear();
+                state.slots.clear();
+                state.closed = true;
+                state.overflowed = true;
+                self.cond.notify_one();
+                return;
+            }
+        }
+        if coalesce {
+            let index = state.items.len();
+            state.slots.insert(key.into(), index);
+        }
+        state.items.push_back((key.into(), msg.into()));
+        self.cond.notify_one();
+    }
+
+    /// Drop the queued updates of keys that the client is no longer
+    /// subscribed to.
+    pub fn retain_keys(&self, mut subscribed: impl FnMut(&str) -> bool) {
+        let mut state = self.state.lock();
+        state.items.retain(|item| item.0.is_empty() || subscribed(&item.0));
+        let State { items, slots, .. } = &mut *state;
+        slots.clear();
+        for (index, item) in items.iter().enumerate() {
+            if self.options.overflow == Overflow::Coalesce && !item.0.is_empty() {
+                slots.insert(item.0.clone(), index);
+            }
+        }
+    }
+
+    /// Stop accepting updates; the writer quits after sending the queued ones.
+    pub fn close(&self) {
+        self.state.lock().closed = true;
+        self.cond.notify_one();
+    }
+
+    /// Wait for queued updates and take all of them, concatenated.  If the
+    /// writer should quit instead, returns whether the client overflowed.
+    fn take(&self) -> Result<String, bool> {
+        let mut state = self.state.lock();
+        while state.items.is_empty() && !state.closed {
+            self.cond.wait(&mut state);
+        }
+        if state.items.is_empty() {
+            return Err(state.overflowed);
+        }
+        state.slots.clear();
+        let mut buf = String::new();
+        for (_, msg) in state.items.drain(..) {
+            buf.push_str(&msg);
+        }
+        Ok(buf)
+    }
+
+    /// Thread that writes the queued updates to the client.
+    pub fn writer(&self, name: &str, mut client: Box<dyn Client>) {
+        loop {
+            match self.take() {
+                Ok(buf) => {
+                    if let Err(err) = client.write(buf.as_bytes()) {
+                        warn!("[{}] write error in updater: {}", name, err);
+                        self.close();
+                        break;
+                    }
+                }
+                Err(overflowed) => {
+                    if overflowed {
+                        warn!("[{}] too many queued updates, disconnecting", name);
+                        // this also ends the client's handler
+                        client.close();
+                    }
+                    break;
+                }
+            }
+        }
+        info!("[{}] update writer quit", name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(queue: &OutQueue) -> Vec<String> {
+        queue.state.lock().items.iter().map(|item| item.1.clone()).collect()
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let push_all = |overflow| {
+            let queue = OutQueue::new(QueueOptions { len: 2, overflow });
+            for (key, msg) in [("a", "a1"), ("b", "b1"), ("a", "a2"), ("c", "c1")] {
+                queue.push(key, msg);
+            }
+            queue
+        };
+        assert_eq!(queued(&push_all(Overflow::DropOldest)), ["a2", "c1"]);
+        // a new key doesn't fit into the queue anymore
+        let queue = push_all(Overflow::DropClient);
+        assert!(queued(&queue).is_empty());
+        assert_eq!(queue.take(), Err(true));
+        // ... unless coalescing, which keeps the latest value of every key
+        let queue = push_all(Overflow::Coalesce);
+        for (key, msg) in [("b", "b2"), ("c", "c2"), ("d", "d1")] {
+            queue.push(key, msg);
+        }
+        assert_eq!(queued(&queue), ["a2", "b2", "c2", "d1"]);
+        queue.retain_keys(|key| key != "b");
+        queue.push("c", "c3");
+        assert_eq!(queued(&queue), ["a2", "c3", "d1"]);
+        queue.close();
+        assert_eq!(queue.take(), Ok("a2c3d1".into()));
+        assert_eq!(queue.take(), Err(false));
+        assert_eq!("drop-oldest".parse(), Ok(Overflow::DropOldest));
+    }
+
+    #[test]
+    fn overflow_blocks() {
+        // blocks of lines are kept whole, and count against the length
+        let queue = OutQueue::new(QueueOptions { len: 2, overflow: Overflow::DropOldest });
+        queue.push_all("s1\ns2\n");
+        queue.push("a", "a1");
+        queue.push("b", "b1");
+        assert_eq!(queued(&queue), ["s1\ns2\n", "b1"]);
+        queue.push_all("t1\nt2\n");
+        assert_eq!(queued(&queue), ["s1\ns2\n", "t1\nt2\n"]);
+        // nothing is left to drop
+        queue.push("c", "c1");
+        assert!(queued(&queue).is_empty());
+        assert_eq!(queue.take(), Err(true));
+
+        let queue = OutQueue::new(QueueOptions { len: 1, overflow: Overflow::Coalesce });
+        queue.push("a", "a1");
+        queue.push_all("s1\ns2\n");
+        assert_eq!(queue.take(), Err(true));
+    }
+}
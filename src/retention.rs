@@ -0,0 +1,170 @@
+// -----------------------------------------------------------------------------
+// A Rust implementation of the NICOS cache server.
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// -----------------------------------------------------------------------------
+//
+//
+//! Retention rules for old history.
+
+/// Seconds per day, the unit of retention times.
+const DAY: f64 = 86400.;
+
+/// A rule for how long the history of some categories is kept.
+#[derive(Clone, Debug, PartialEq, Hash)]
+pub struct Rule {
+    /// Category that the rule applies to, together with its subcategories.
+    /// Empty for all categories.
+    pub category:  String,
+    /// Number of days for which all values are kept.
+    pub raw_days:  u32,
+    /// Number of days for which anything is kept; between `raw_days` and this,
+    /// only the last value of each key and day is kept.  None for forever.
+    pub keep_days: Option<u32>,
+}
+
+impl Rule {
+    /// Parse a rule given as `[CATEGORY=]DAYS[,daily[=DAYS]]`.
+    pub fn parse(spec: &str) -> Result<Rule, String> {
+        let (rule, daily) = match spec.split_once(',') {
+            Some((rule, daily)) => (rule, Some(daily)),
+            None => (spec, None),
+        };
+        let (category, raw_days) = rule.rsplit_once('=').unwrap_or(("", rule));
+        let raw_days = match raw_days.parse() {
+            Ok(days) if days > 0 => days,
+            _ => return Err(format!("invalid number of days in retention rule {:?}", spec)),
+        };
+        let keep_days = match daily {
+            None => Some(raw_days),
+            Some("daily") => None,
+            Some(daily) => match daily.strip_prefix("daily=").map(str::parse) {
+                Some(Ok(days)) if days >= raw_days => Some(days),
+                _ => return Err(format!("invalid daily part in retention rule {:?}", spec)),
+            }
+        };
+        Ok(Rule { category: category.trim_end_matches('/').into(), raw_days, keep_days })
+    }
+
+    /// Does the rule apply to this category?
+    pub fn applies_to(&self, catname: &str) -> bool {
+        self.category.is_empty() || catname == self.category ||
+            catname.strip_prefix(&self.category).map_or(false, |rest| rest.starts_with('/'))
+    }
+
+    /// Timestamp before which values are thinned out or removed.
+    pub fn raw_before(&self, now: f64) -> f64 {
+        now - self.raw_days as f64 * DAY
+    }
+
+    /// Timestamp before which values are removed, if any.
+    pub fn keep_before(&self, now: f64) -> Option<f64> {
+        self.keep_days.map(|days| now - days as f64 * DAY)
+    }
+
+    /// Are values between the two timestamps thinned out to daily values?
+    pub fn thins(&self) -> bool {
+        self.keep_days.map_or(true, |days| days > self.raw_days)
+    }
+}
+
+/// A set of retention rules.  Categories without a matching rule are kept
+/// forever.
+#[derive(Clone, Debug, Default)]
+pub struct Retention {
+    rules: Vec<Rule>,
+}
+
+impl Retention {
+    pub fn new(rules: Vec<Rule>) -> Retention {
+        Retention { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    #[cfg_attr(not(any(feature = "postgres", feature = "sqlite")), allow(dead_code))]
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Get the rule for a category, which is the one with the most specific
+    /// category that applies.
+    pub fn rule_for(&self, catname: &str) -> Option<&Rule> {
+        self.rules.iter().filter(|rule| rule.applies_to(catname))
+                         .max_by_key(|rule| rule.category.len())
+    }
+
+    /// Build an SQL condition that selects the keys which a rule is responsible
+    /// for.  Returns the condition and the values of its parameters, which are
+    /// named by `param` from their index (starting at `first`).
+    #[cfg_attr(not(any(feature = "postgres", feature = "sqlite")), allow(dead_code))]
+    pub fn sql_key_filter(&self, rule: &Rule, first: usize, param: fn(usize) -> String)
+                          -> (String, Vec<String>) {
+        let mut cond = String::from("TRUE");
+        let mut values = Vec::new();
+        let mut add = |cond: &mut String, negate: bool, category: &str| {
+            let prefix = format!("{}/", category);
+            cond.push_str(&format!(" AND {}substr(key, 1, {}) = {}", if negate { "NOT " } else { "" },
+                                   prefix.chars().count(), param(first + values.len())));
+            values.push(prefix);
+        };
+        if !rule.category.is_empty() {
+            add(&mut cond, false, &rule.category);
+        }
+        // keys of more specific rules are left to them
+        for other in &self.rules {
+            if other.category.len() > rule.category.len() && rule.applies_to(&other.category) {
+                add(&mut cond, true, &other.category);
+            }
+        }
+        (cond, values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rules() {
+        let rule = |category: &str, raw_days, keep_days| Rule {
+            category: category.into(), raw_days, keep_days
+        };
+        assert_eq!(Rule::parse("90"), Ok(rule("", 90, Some(90))));
+        assert_eq!(Rule::parse("90,daily"), Ok(rule("", 90, None)));
+        assert_eq!(Rule::parse("nicos/det=7"), Ok(rule("nicos/det", 7, Some(7))));
+        assert_eq!(Rule::parse("nicos/=30,daily=365"), Ok(rule("nicos", 30, Some(365))));
+        for invalid in ["0", "x=", "7,weekly", "30,daily=7", ""] {
+            assert!(Rule::parse(invalid).is_err());
+        }
+
+        let retention = Retention::new(vec![rule("", 90, None), rule("nicos", 30, None),
+                                            rule("nicos/det", 7, Some(7))]);
+        assert_eq!(retention.rule_for("other").unwrap().raw_days, 90);
+        assert_eq!(retention.rule_for("nicos").unwrap().raw_days, 30);
+        assert_eq!(retention.rule_for("nicos/detector").unwrap().raw_days, 30);
+        assert_eq!(retention.rule_for("nicos/det/sub").unwrap().raw_days, 7);
+        let (cond, values) = retention.sql_key_filter(&retention.rules()[1], 3,
+                                                      |i| format!("${}", i));
+        assert_eq!(cond, "TRUE AND substr(key, 1, 6) = $3 AND NOT substr(key, 1, 10) = $4");
+        assert_eq!(values, ["nicos/", "nicos/det/"]);
+    }
+}
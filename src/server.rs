@@ -22,49 +22,94 @@
 //
 //! This module contains the server instance itself.
 
+use std::collections::BTreeMap;
 use std::io::{self, Read, Write};
 use std::net::{SocketAddr, TcpStream, TcpListener, UdpSocket, Shutdown};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use log::{info, warn};
-use parking_lot::Mutex;
-use crossbeam_channel::{unbounded, Sender, Receiver};
+use mlzutil::time::localtime;
+use crossbeam_channel::{unbounded, Sender, Receiver, RecvTimeoutError};
 use mlzutil::fs::abspath;
 
 use crate::handler::{Updater, Handler, UpdaterMsg};
-use crate::database::{ThreadsafeDB, DB, Store};
+use crate::outqueue::QueueOptions;
+use crate::database::{ThreadsafeDB, DB, Store, HistoryPruner};
+use crate::retention::Retention;
 use crate::store_flat::Store as FlatStore;
+use crate::store_log::Store as LogStore;
+use crate::store_memory::Store as MemoryStore;
+use crate::store_tee::Store as TeeStore;
 #[cfg(feature = "postgres")]
 use crate::store_pgsql::Store as PgSqlStore;
+#[cfg(feature = "sqlite")]
+use crate::store_sqlite::Store as SqliteStore;
 
 pub const RECVBUF_LEN: usize = 4096;
 
+/// Interval between runs of the history pruner.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
 pub type ClientAddr = SocketAddr;
 
 /// Represents different ways to specify a store path.
 pub enum StorePath {
     /// Specified as a normal filesystem path.  Uses the flat-file backend.
     Fs(PathBuf),
-    /// Specified as an URI.  Currently only the postgresql:// scheme is supported.
+    /// Specified as an URI.  Currently the postgresql://, sqlite:// and log://
+    /// schemes are supported.
     Uri(String),
+    /// Specified as memory://[snapshot path][?history=N].  Uses the in-memory
+    /// backend.
+    Memory(Option<PathBuf>, usize),
 }
 
+/// Default number of history entries per key for the in-memory store.
+const MEMORY_HISTORY_LEN: usize = 1000;
+
 impl StorePath {
     pub fn parse(path: &str) -> Result<StorePath, &'static str> {
         if path.contains("://") {
             if path.starts_with("postgresql://") {
                 Ok(StorePath::Uri(path.to_string()))
+            } else if let Some(file) = path.strip_prefix("sqlite://") {
+                Ok(StorePath::Uri(format!("sqlite://{}", abspath(file).display())))
+            } else if let Some(dir) = path.strip_prefix("log://") {
+                Ok(StorePath::Uri(format!("log://{}", abspath(dir).display())))
+            } else if let Some(spec) = path.strip_prefix("memory://") {
+                let (file, len) = match spec.split_once("?history=") {
+                    Some((file, len)) => (file, len.parse().map_err(|_| "invalid history length")?),
+                    None => (spec, MEMORY_HISTORY_LEN),
+                };
+                if len == 0 {
+                    return Err("invalid history length");
+                }
+                let snapshot = if file.is_empty() { None } else { Some(abspath(file)) };
+                Ok(StorePath::Memory(snapshot, len))
             } else {
                 Err("the given URI scheme is not supported")
             }
         } else {
-            Ok(StorePath::Fs(abspath(&path)))
+            Ok(StorePath::Fs(abspath(path)))
         }
     }
 }
 
+/// Options for the store backends.
+#[derive(Default)]
+pub struct StoreOptions {
+    /// Maintain a time index for flat-file history lookups?
+    pub history_index: bool,
+    /// Further stores that all entries are written to.
+    pub mirrors: Vec<StorePath>,
+    /// Compress flat-file store days that are at least this many days old.
+    pub compress_after: Option<u32>,
+    /// Rules for pruning old history.
+    pub retention: Retention,
+}
+
 /// A trait abstracting our notion of a client -- could be TCP or UDP sockets in
 /// the IP or Unix domain.
 pub trait Client : Send {
@@ -132,29 +177,38 @@ impl Client for UdpClient {
 /// - cleaner: goes through the database periodically, marks entries with TTL as
 ///   expired when needed
 /// - updater: receives "update" messages from the database and handlers, and
-///   sends key updates to clients who have subscribed to the key
+///   queues key updates for clients who have subscribed to the key
+/// - writers: one for each TCP client, sends its queued updates
 /// - listeners: one listener for each server socket (UDP and TCP)
 /// - handlers: each listener thread can spawn handler threads when a connection
 ///   comes in; each thread runs a Handler's main function
 pub struct Server {
     db:    ThreadsafeDB,
     upd_q: Sender<UpdaterMsg>,
+    queue: QueueOptions,
 }
 
 impl Server {
-    pub fn new(storepath: StorePath, clear_db: bool) -> Result<Server, ()> {
+    pub fn new(storepath: StorePath, options: StoreOptions, queue: QueueOptions,
+               clear_db: bool) -> Result<Server, ()> {
         // create a channel to send updated keys to the updater thread
         let (w_updates, r_updates) = unbounded();
 
-        // create the database object itself and wrap it into the mutex
-        let store: Box<dyn Store> = match storepath {
-            StorePath::Fs(path) => Box::new(FlatStore::new(path)),
-            StorePath::Uri(ref uri) if uri.starts_with("postgresql://") => {
-                Self::make_postgres_store(uri)?
+        // create the database object itself (it does its own locking)
+        let mut store = Self::make_store(storepath, &options)?;
+        if !options.mirrors.is_empty() {
+            let mut mirrors = Vec::new();
+            for (i, path) in options.mirrors.into_iter().enumerate() {
+                let name = format!("mirror store #{}", i + 1);
+                match Self::make_store(path, &StoreOptions::default()) {
+                    Ok(mirror) => mirrors.push((name, mirror)),
+                    Err(()) => warn!("{} could not be created, not using it", name),
+                }
             }
-            StorePath::Uri(uri) => panic!("store URI {} not supported", uri)
-        };
-        let mut db = DB::new(store, w_updates.clone());
+            store = Box::new(TeeStore::new(store, mirrors));
+        }
+        let pruner = if options.retention.is_empty() { None } else { store.history_pruner() };
+        let db = DB::new(store, w_updates.clone());
         if clear_db {
             info!("clearing stored database...");
             if let Err(e) = db.clear_db() {
@@ -166,16 +220,51 @@ impl Server {
                 warn!("could not read existing database: {}", e);
             }
         }
-        let db = Arc::new(Mutex::new(db));
+        let db = Arc::new(db);
+
+        // the updater sends the updates numbered after this, including expiries
+        let start_seq = db.last_seq();
 
         // start a thread that cleans the DB periodically of expired entries
         let db_clone = db.clone();
         thread::spawn(move || Server::cleaner(db_clone));
 
         // start a thread that sends out updates to connected clients
-        thread::spawn(move || Server::updater(r_updates));
+        thread::spawn(move || Server::updater(r_updates, start_seq));
+
+        // start a thread that removes old history, if configured
+        if let Some(pruner) = pruner {
+            let retention = options.retention;
+            thread::spawn(move || Server::pruner(pruner, retention));
+        } else if !options.retention.is_empty() {
+            warn!("store does not support retention rules, keeping all history");
+        }
+
+        Ok(Server { db, upd_q: w_updates, queue })
+    }
+
+    /// Create the store backend for a store path.
+    pub fn make_store(storepath: StorePath, options: &StoreOptions) -> Result<Box<dyn Store>, ()> {
+        Ok(match storepath {
+            StorePath::Fs(path) => Box::new(FlatStore::new(path, options.history_index,
+                                                              options.compress_after)),
+            StorePath::Uri(ref uri) if uri.starts_with("postgresql://") => {
+                Self::make_postgres_store(uri)?
+            }
+            StorePath::Memory(snapshot, len) => Box::new(MemoryStore::new(snapshot, len)),
+            StorePath::Uri(ref uri) if uri.starts_with("log://") => {
+                Box::new(LogStore::new(uri["log://".len()..].into()))
+            }
+            StorePath::Uri(ref uri) if uri.starts_with("sqlite://") => {
+                Self::make_sqlite_store(&uri["sqlite://".len()..])?
+            }
+            StorePath::Uri(uri) => panic!("store URI {} not supported", uri)
+        })
+    }
 
-        Ok(Server { db, upd_q: w_updates })
+    /// Get the database, e.g. to shut it down after the server has been started.
+    pub fn db(&self) -> ThreadsafeDB {
+        self.db.clone()
     }
 
     #[cfg(feature = "postgres")]
@@ -194,47 +283,116 @@ impl Server {
         panic!("not compiled with postgres support")
     }
 
+    #[cfg(feature = "sqlite")]
+    fn make_sqlite_store(path: &str) -> Result<Box<dyn Store>, ()> {
+        match SqliteStore::new(path.as_ref()) {
+            Ok(store) => Ok(Box::new(store)),
+            Err(err) => {
+                log::error!("could not open SQLite database: {}", err);
+                Err(())
+            }
+        }
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn make_sqlite_store(_: &str) -> Result<Box<dyn Store>, ()> {
+        panic!("not compiled with sqlite support")
+    }
+
     /// Periodically call the database's "clean" function, which searches for
     /// expired keys and updates clients about the expiration.
     fn cleaner(db: ThreadsafeDB) {
         info!("cleaner started");
         loop {
             thread::sleep(Duration::from_millis(250));
-            {
-                let mut db = db.lock();
-                db.clean();
+            db.clean();
+        }
+    }
+
+    /// Periodically remove history that is older than the retention rules allow.
+    fn pruner(mut pruner: Box<dyn HistoryPruner>, retention: Retention) {
+        info!("pruner started");
+        loop {
+            if let Err(e) = pruner.prune(&retention, localtime()) {
+                warn!("could not prune old history: {}", e);
             }
+            thread::sleep(PRUNE_INTERVAL);
         }
     }
 
     /// Receive key updates from the database, and distribute them to all
     /// connected clients.
-    fn updater(chan: Receiver<UpdaterMsg>) {
+    fn updater(chan: Receiver<UpdaterMsg>, start_seq: u64) {
         info!("updater started");
         let mut updaters: Vec<Updater> = Vec::with_capacity(8);
-        for item in chan {
+        // sequence number of the last update, which subscriptions start after
+        let mut last_seq = start_seq;
+        // updates that arrived before one with a lower sequence number
+        let mut early = BTreeMap::new();
+        loop {
+            // wake up when held-back updates of rate-limited subscriptions are due
+            let item = match updaters.iter().filter_map(Updater::next_due).min() {
+                Some(deadline) => match chan.recv_deadline(deadline) {
+                    Ok(item) => Some(item),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match chan.recv() {
+                    Ok(item) => Some(item),
+                    Err(_) => break,
+                },
+            };
+            let now = Instant::now();
+            for upd in &mut updaters {
+                upd.flush(now);
+            }
+            let item = match item {
+                Some(item) => item,
+                None => continue,
+            };
             match item {
-                UpdaterMsg::Update(mut entry, source) => {
-                    for upd in &updaters {
-                        match source {
-                            // if the update came from a certain client, do not send it
-                            // back to this client
-                            Some(a) if a == upd.addr => continue,
-                            _ => upd.update(&mut entry),
+                // updates numbered before the updater started are not sent
+                UpdaterMsg::Update(entry, _) if entry.seq() <= last_seq => (),
+                UpdaterMsg::Update(entry, source) => {
+                    early.insert(entry.seq(), (entry, source));
+                    while let Some((entry, source)) = early.remove(&(last_seq + 1)) {
+                        last_seq = entry.seq();
+                        for upd in &mut updaters {
+                            match source {
+                                // if the update came from a certain client, do not send it
+                                // back to this client
+                                Some(a) if a == upd.addr => continue,
+                                _ => upd.update(&entry, now),
+                            }
                         }
                     }
                 },
                 UpdaterMsg::NewUpdater(updater) => {
                     updaters.push(*updater);
                 },
-                UpdaterMsg::Subscription(addr, key, with_ts) => {
+                UpdaterMsg::Subscription(addr, pattern, with_ts, opts) => {
+                    if let Some(upd) = updaters.iter_mut().find(|u| u.addr == addr) {
+                        upd.add_subscription(pattern, with_ts, opts, last_seq);
+                    }
+                },
+                UpdaterMsg::Snapshot(addr, lines) => {
+                    if let Some(upd) = updaters.iter().find(|u| u.addr == addr) {
+                        upd.snapshot(&lines);
+                    }
+                },
+                UpdaterMsg::Replay(addr, entries, reply) => {
+                    if let Some(upd) = updaters.iter_mut().find(|u| u.addr == addr) {
+                        upd.replay(&entries, &reply, now);
+                    }
+                },
+                UpdaterMsg::CancelSubscription(addr, kind, key, with_ts) => {
                     if let Some(upd) = updaters.iter_mut().find(|u| u.addr == addr) {
-                        upd.add_subscription(key, with_ts);
+                        upd.remove_subscription(kind, key, with_ts);
                     }
                 },
-                UpdaterMsg::CancelSubscription(addr, key, with_ts) => {
+                UpdaterMsg::SetOptions(addr, opts) => {
                     if let Some(upd) = updaters.iter_mut().find(|u| u.addr == addr) {
-                        upd.remove_subscription(key, with_ts);
+                        upd.set_options(opts);
                     }
                 },
                 UpdaterMsg::RemoveUpdater(addr) => {
@@ -271,7 +429,7 @@ impl Server {
             info!("[{}] new client connected", addr);
             // create the updater object and insert it into the mapping
             let upd_client = client.try_clone().expect("could not clone socket");
-            let updater = Updater::new(upd_client, addr);
+            let updater = Updater::new(upd_client, addr, self.queue);
             let _ = self.upd_q.send(UpdaterMsg::NewUpdater(Box::new(updater)));
 
             // create the handler and start its main thread
@@ -22,19 +22,54 @@
 //
 //! Flat-file database store.
 
-use std::mem;
-use std::fs::{File, OpenOptions, read_dir, remove_file, hard_link, remove_dir_all};
-use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
-use std::os::unix::fs::symlink;
+use std::{mem, str, thread};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions, read_dir, remove_file, hard_link, remove_dir_all, rename};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{symlink, MetadataExt};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use log::{info, warn};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
 use chrono::{DateTime, Datelike, Duration, Local, Utc, TimeZone};
 use hashbrown::HashMap;
+use parking_lot::Mutex;
 use mlzutil::fs::ensure_dir;
 use mlzutil::time::{to_timespec, to_timefloat};
 
-use crate::database::{self, EntryMap};
-use crate::entry::{Entry, split_key};
+use crate::database::{self, EntryMap, HistLimit, LimitedSender};
+use crate::entry::{Entry, split_key, construct_key};
+use crate::message::{escape, unescape};
+use crate::retention::Retention;
+
+/// First line of every store file.
+const STORE_HEADER: &str = "# NICOS cache store file v2";
+
+/// Directory where fsck puts lines that it removes from store files.
+const LOST_DIR: &str = "lost+found";
+
+/// Marker in an optional fifth column that says the value is escaped.
+///
+/// Only values that contain tabs or line breaks are written like this, so that
+/// all other lines stay compatible with older readers.
+const ESCAPED_MARK: &str = "esc";
+
+/// Subdirectory of a day directory that holds the history index files.
+const INDEX_DIR: &str = ".index";
+
+/// Width of the time buckets in the history index, in seconds.
+const INDEX_BUCKET: f64 = 300.;
+
+/// Number of saved entries whose index records are written to the index file
+/// together.  Queries read the few entries after that from the store file.
+const INDEX_BATCH: usize = 100;
+
+/// Extension of compressed store files and their category links.
+const COMPRESSED_EXT: &str = ".gz";
+
+/// Line in store files that have been thinned out to daily values.
+const THINNED_MARK: &str = "# thinned out to daily values";
 
 /// Get the store subdir for a certain day.
 pub fn day_path<T: TimeZone>(day: DateTime<T>) -> String {
@@ -50,6 +85,8 @@ pub fn all_days(from: f64, to: f64) -> Vec<String> {
     let mut res = Vec::new();
     let to = to_timespec(to);
     let mut tm = to_timespec(from);
+    // start at the beginning of the first day, to include the last day in any case
+    tm = tm.with_time(Default::default()).earliest().unwrap_or(tm);
     while tm < to {
         res.push(day_path(tm));
         tm += Duration::days(1);
@@ -57,13 +94,306 @@ pub fn all_days(from: f64, to: f64) -> Vec<String> {
     res
 }
 
+/// Create a new file for a category in the directory of a day.
+///
+/// The tidy lock is needed to uncompress the file of an old day.
+fn create_fd(storepath: &Path, catname: &str, ymd_path: &str, tidy: &Mutex<()>)
+             -> io::Result<File> {
+    let safe_catname = catname.replace('/', "-");
+    let subpath = storepath.join(ymd_path);
+    let linkfile = storepath.join(&safe_catname).join(ymd_path);
+    ensure_dir(&subpath)?;
+    let gz_path = subpath.join(format!("{}{}", safe_catname, COMPRESSED_EXT));
+    if gz_path.is_file() {
+        let _tidy = tidy.lock();
+        // entries can only be added to an uncompressed file; check again,
+        // since it could have been pruned meanwhile
+        if gz_path.is_file() {
+            uncompress_file(storepath, ymd_path, &safe_catname)?;
+        }
+    }
+    let file = subpath.join(safe_catname);
+    let mut fp = OpenOptions::new().create(true).append(true).open(&file)?;
+    if fp.stream_position()? == 0 {
+        writeln!(fp, "{}", STORE_HEADER)?;
+    }
+    ensure_dir(linkfile.parent().unwrap())?;
+    if !linkfile.is_file() {
+        hard_link(file, linkfile)?;
+    }
+    Ok(fp)
+}
+
+/// Get the sorted names of the year or day subdirectories of a path.
+fn day_dirs(path: &Path, name_len: usize) -> io::Result<Vec<String>> {
+    let mut res = read_dir(path)?.flatten()
+        .filter(|dentry| dentry.file_type().map_or(false, |t| t.is_dir()))
+        .filter_map(|dentry| dentry.file_name().into_string().ok())
+        .filter(|name| name.len() == name_len &&
+                name.bytes().all(|b| b.is_ascii_digit() || b == b'-'))
+        .collect::<Vec<_>>();
+    res.sort_unstable();
+    Ok(res)
+}
+
+/// Get the names of the category link directories.
+fn category_dirs(storepath: &Path) -> io::Result<Vec<String>> {
+    Ok(read_dir(storepath)?.flatten()
+        .filter(|dentry| dentry.file_type().map_or(false, |t| t.is_dir()))
+        .filter_map(|dentry| dentry.file_name().into_string().ok())
+        .filter(|name| !name.starts_with('.') && name != LOST_DIR &&
+                !(name.len() == 4 && name.bytes().all(|b| b.is_ascii_digit())))
+        .collect())
+}
+
+/// Get the category link for a store file of a day, which can be compressed.
+fn category_link(storepath: &Path, day: &str, filename: &str) -> PathBuf {
+    match filename.strip_suffix(COMPRESSED_EXT) {
+        Some(catname) => storepath.join(catname).join(format!("{}{}", day, COMPRESSED_EXT)),
+        None => storepath.join(filename).join(day),
+    }
+}
+
+/// Get the category name for a store file.
+fn file_catname(path: &Path) -> String {
+    let name = path.file_name().unwrap().to_string_lossy();
+    name.strip_suffix(COMPRESSED_EXT).unwrap_or(&name).replace('-', "/")
+}
+
+/// Get the path of the temporary file used to (un)compress a store file.
+fn compress_tmp_path(storepath: &Path, day: &str, catname: &str) -> PathBuf {
+    storepath.join(format!(".{}-{}.tmp", day.replace('/', "-"), catname))
+}
+
+/// Compress all store files of the days up to and including the given one.
+fn compress_days(storepath: &Path, last_day: &str) -> io::Result<()> {
+    for year in day_dirs(storepath, 4)? {
+        for day in day_dirs(&storepath.join(&year), 5)? {
+            let day = format!("{}/{}", year, day);
+            if day.as_str() > last_day {
+                return Ok(());
+            }
+            let mut ncompressed = 0;
+            for dentry in read_dir(storepath.join(&day))?.flatten() {
+                match dentry.file_name().into_string() {
+                    Ok(name) if dentry.file_type().map_or(false, |t| t.is_file()) &&
+                        !name.starts_with('.') && !name.ends_with(COMPRESSED_EXT) => {
+                        compress_file(storepath, &day, &name)?;
+                        ncompressed += 1;
+                    }
+                    _ => ()
+                }
+            }
+            if ncompressed > 0 {
+                let _ = fs::remove_dir(storepath.join(&day).join(INDEX_DIR));
+                info!("compressed {} store files of {}", ncompressed, day);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace a store file, and its category link, by a compressed copy.
+fn compress_file(storepath: &Path, day: &str, catname: &str) -> io::Result<()> {
+    let path = storepath.join(day).join(catname);
+    let gz_path = storepath.join(day).join(format!("{}{}", catname, COMPRESSED_EXT));
+    let tmp_path = compress_tmp_path(storepath, day, catname);
+    let mut encoder = GzEncoder::new(File::create(&tmp_path)?, Compression::default());
+    io::copy(&mut File::open(&path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    rename(&tmp_path, &gz_path)?;
+    let gz_linkfile = category_link(storepath, day, &format!("{}{}", catname, COMPRESSED_EXT));
+    let _ = remove_file(&gz_linkfile);
+    ensure_dir(gz_linkfile.parent().unwrap())?;
+    hard_link(&gz_path, gz_linkfile)?;
+    // readers switch to the compressed file once the original is gone
+    remove_file(path)?;
+    let _ = remove_file(storepath.join(catname).join(day));
+    let _ = remove_file(storepath.join(day).join(INDEX_DIR).join(catname));
+    Ok(())
+}
+
+/// Replace a compressed store file, and its category link, by an uncompressed
+/// copy.
+fn uncompress_file(storepath: &Path, day: &str, catname: &str) -> io::Result<()> {
+    let path = storepath.join(day).join(catname);
+    let gz_path = storepath.join(day).join(format!("{}{}", catname, COMPRESSED_EXT));
+    let tmp_path = compress_tmp_path(storepath, day, catname);
+    let mut fp = File::create(&tmp_path)?;
+    io::copy(&mut GzDecoder::new(File::open(&gz_path)?), &mut fp)?;
+    fp.sync_all()?;
+    // like an interrupted compression, the uncompressed file wins from here on
+    rename(&tmp_path, &path)?;
+    let linkfile = category_link(storepath, day, catname);
+    let _ = remove_file(&linkfile);
+    ensure_dir(linkfile.parent().unwrap())?;
+    hard_link(&path, linkfile)?;
+    remove_file(gz_path)?;
+    let _ = remove_file(category_link(storepath, day, &format!("{}{}", catname, COMPRESSED_EXT)));
+    Ok(())
+}
+
+/// Apply an entry read from a store file to the map of its category, where
+/// it becomes the latest value.
+pub fn apply_stored(map: &mut HashMap<String, Entry>, subkey: &str, parts: &[&str]) {
+    if parts[2] == "+" {
+        // value is non-expiring: we can take it as valid
+        if let Ok(v) = parts[1].parse() {
+            map.insert(subkey.into(), Entry::new(v, 0., parts[3]));
+        }
+    } else if parts[3] != "-" {
+        // value was expiring but is not empty: take it as expired
+        if let Ok(v) = parts[1].parse() {
+            map.insert(subkey.into(), Entry::new(v, 0., parts[3]).expired());
+        }
+    } else if let Some(entry) = map.get_mut(subkey) {
+        // value is empty: be sure to mark any current value as expired
+        entry.expired = true;
+    }
+}
+
 impl Entry {
     /// Write the Entry to a store file.
-    fn to_file(&self, subkey: &str, fp: &mut File) -> io::Result<()> {
+    ///
+    /// The line is written in one go, so that concurrent readers of the file
+    /// see either all or nothing of it.
+    pub fn to_file<W: Write>(&self, subkey: &str, fp: &mut W) -> io::Result<()> {
         let ttlsign = if self.ttl > 0. || self.expired { "-" } else { "+" };
-        writeln!(fp, "{}\t{}\t{}\t{}",
-                 subkey, self.time, ttlsign,
-                 if self.expired { "-" } else { &self.value })
+        let line = if self.expired {
+            format!("{}\t{}\t{}\t-\n", subkey, self.time, ttlsign)
+        } else if self.value.bytes().any(|b| matches!(b, b'\t' | b'\r' | b'\n')) {
+            format!("{}\t{}\t{}\t{}\t{}\n",
+                    subkey, self.time, ttlsign, escape(&self.value), ESCAPED_MARK)
+        } else {
+            format!("{}\t{}\t{}\t{}\n", subkey, self.time, ttlsign, self.value)
+        };
+        fp.write_all(line.as_bytes())
+    }
+}
+
+/// Get the history index bucket for a timestamp.
+pub fn index_bucket(time: f64) -> i64 {
+    (time / INDEX_BUCKET).floor() as i64
+}
+
+/// The history index for a single store file.
+///
+/// For each subkey and time bucket, it records the range of the store file
+/// that contains all entries of the subkey within that bucket.  On disk, the
+/// index is only ever appended to, and records up to which offset of the store
+/// file it is complete.
+#[derive(Default)]
+pub struct FileIndex {
+    /// Length of the store file that is covered by the index.
+    pub covered: u64,
+    /// File ranges by subkey and bucket.
+    ranges: HashMap<String, BTreeMap<i64, (u64, u64)>>,
+}
+
+impl FileIndex {
+    /// Load an index file.  A missing file gives an empty index.
+    pub fn load(indexpath: &Path) -> io::Result<FileIndex> {
+        let mut index = FileIndex::default();
+        if let Ok(fp) = File::open(indexpath) {
+            let mut reader = BufReader::new(fp);
+            let mut line = String::new();
+            while reader.read_line(&mut line)? > 0 && line.ends_with('\n') {
+                if let Some(covered) = line.strip_prefix("# covered ") {
+                    // the store and queries can both append to the index
+                    index.covered = index.covered.max(covered.trim().parse().unwrap_or(0));
+                } else if let [subkey, bucket, start, end] = line.trim().split('\t')
+                                                                 .collect::<Vec<_>>()[..] {
+                    if let (Ok(bucket), Ok(start), Ok(end)) =
+                        (bucket.parse(), start.parse(), end.parse()) {
+                        index.add(subkey, bucket, start, end);
+                    }
+                }
+                line.clear();
+            }
+        }
+        Ok(index)
+    }
+
+    /// Append the records of this index to an index file, which is then
+    /// complete up to the given offset of the store file.
+    pub fn append_to(&self, indexpath: &Path, covered: u64) -> io::Result<()> {
+        ensure_dir(indexpath.parent().unwrap())?;
+        let mut fp = OpenOptions::new().create(true).append(true).open(indexpath)?;
+        // write all new records at once, so that concurrent updates of the
+        // index don't interleave
+        let mut block = String::new();
+        if fp.metadata()?.len() == 0 {
+            block.push_str("# NICOS cache index file v1\n");
+        }
+        for (subkey, buckets) in &self.ranges {
+            for (&bucket, &(start, end)) in buckets {
+                block.push_str(&format!("{}\t{}\t{}\t{}\n", subkey, bucket, start, end));
+            }
+        }
+        block.push_str(&format!("# covered {}\n", covered));
+        fp.write_all(block.as_bytes())
+    }
+
+    pub fn add(&mut self, subkey: &str, bucket: i64, start: u64, end: u64) {
+        let range = self.ranges.entry_ref(subkey).or_default()
+                                                  .entry(bucket).or_insert((start, end));
+        range.0 = range.0.min(start);
+        range.1 = range.1.max(end);
+    }
+
+    /// Get the (merged and sorted) file ranges that can contain entries for a
+    /// subkey within the given time span.
+    pub fn ranges(&self, subkey: &str, from: f64, to: f64) -> Vec<(u64, u64)> {
+        let mut ranges = match self.ranges.get(subkey) {
+            Some(buckets) => buckets.range(index_bucket(from)..=index_bucket(to))
+                                    .map(|(_, &range)| range).collect(),
+            None => Vec::new(),
+        };
+        ranges.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+    }
+}
+
+/// Keeps the history index of a store file of the current day up to date
+/// while entries are saved.
+struct IndexUpdate {
+    /// Path of the index file.
+    indexpath:    PathBuf,
+    /// Offset in the store file up to which entries are indexed.
+    covered:      u64,
+    /// Index records that are not yet written to the index file.
+    pending:      FileIndex,
+    /// Number of entries in the pending records.
+    npending:     usize,
+}
+
+impl IndexUpdate {
+    /// Add the entry that has just been written to the store file, which now
+    /// ends at the given offset.
+    fn add(&mut self, subkey: &str, time: f64, end: u64) -> io::Result<()> {
+        self.pending.add(subkey, index_bucket(time), self.covered, end);
+        self.covered = end;
+        self.npending += 1;
+        if self.npending >= INDEX_BATCH {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write the pending records to the index file.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.npending > 0 {
+            mem::take(&mut self.pending).append_to(&self.indexpath, self.covered)?;
+            self.npending = 0;
+        }
+        Ok(())
     }
 }
 
@@ -75,19 +405,68 @@ pub struct Store {
     ymd_path:     String,
     /// Map of store files, by categories.
     files:        HashMap<String, File>,
+    /// Index updates for the store files, by categories, if enabled.
+    indexes:      HashMap<String, IndexUpdate>,
     /// Last and next midnight as floating timestamps.
     midnights:    (f64, f64),
+    /// Reader for the history files.
+    history:      History,
+    /// State of an ongoing import.
+    import:       Option<Import>,
+    /// Compress the files of days that are at least this many days old.
+    compress_after: Option<u32>,
+    /// Thread that compresses old days.
+    compressor:   Option<thread::JoinHandle<()>>,
+    /// Held while the files of old days are compressed, uncompressed or
+    /// pruned, which can happen in different threads.
+    tidy:         Arc<Mutex<()>>,
+}
+
+/// State of an import of entries from another store.
+#[derive(Default)]
+struct Import {
+    /// Latest day that has been imported into.
+    day:          String,
+    /// Store files of that day, by categories.
+    files:        HashMap<String, File>,
+    /// Latest imported entries.
+    latest:       EntryMap,
+}
+
+/// Read-only access to the history in the store files, independent of the
+/// store that writes them.
+#[derive(Clone)]
+pub struct History {
+    /// Root path for cache file storage.
+    storepath:    PathBuf,
+    /// Use (and maintain) the history index files?
+    use_index:    bool,
+}
+
+/// Removes old history from the store files, independent of the store that
+/// writes them.
+pub struct Pruner {
+    /// Root path for cache file storage.
+    storepath:    PathBuf,
+    /// Shared with the store, see there.
+    tidy:         Arc<Mutex<()>>,
 }
 
 impl Store {
-    pub fn new(storepath: PathBuf) -> Store {
+    pub fn new(storepath: PathBuf, use_index: bool, compress_after: Option<u32>) -> Store {
         let thisday = thisday();
         Store {
+            history: History { storepath: storepath.clone(), use_index },
             storepath,
             files: HashMap::default(),
+            indexes: HashMap::default(),
             midnights: (to_timefloat(thisday),
                         to_timefloat(thisday + Duration::days(1))),
             ymd_path: day_path(thisday),
+            import: None,
+            compress_after,
+            compressor: None,
+            tidy: Arc::default(),
         }
     }
 }
@@ -98,7 +477,8 @@ impl database::Store for Store {
         if self.storepath.is_dir() {
             remove_dir_all(&self.storepath)?;
             ensure_dir(&self.storepath)?;
-            self.set_lastday();
+            self.set_lastday(&self.ymd_path);
+            self.indexes.clear();
         }
         Ok(())
     }
@@ -118,7 +498,7 @@ impl database::Store for Store {
         }
         if !p.is_dir() {
             info!("no previous values found, setting \"lastday\" link");
-            self.set_lastday();
+            self.set_lastday(&self.ymd_path);
             return Ok(());
         }
 
@@ -130,8 +510,7 @@ impl database::Store for Store {
                 let path = dentry.path();
                 match self.load_one_file(&path) {
                     Ok(map) => {
-                        let catname = path.file_name().unwrap().to_string_lossy()
-                                                               .replace('-', "/");
+                        let catname = file_catname(&path);
                         nentries += map.len();
                         nfiles += 1;
                         entry_map.insert(catname, map);
@@ -144,43 +523,187 @@ impl database::Store for Store {
             }
         }
         info!("db: read {} entries from {} storefiles", nentries, nfiles);
+        self.compress_old_days();
         if need_rollover {
-            self.rollover(entry_map)
+            self.rollover_files(&[entry_map])
         } else {
             Ok(())
         }
     }
 
-    /// Roll over store files when needed.
-    fn tell_hook(&mut self, entry: &Entry, entry_map: &mut EntryMap) -> io::Result<()> {
-        if entry.time >= self.midnights.1 {
-            self.rollover(entry_map)?;
-        }
-        Ok(())
+    /// Store files must be rolled over after midnight.
+    fn needs_rollover(&self, time: f64) -> bool {
+        time >= self.midnights.1
+    }
+
+    /// Roll over store files.
+    fn rollover(&mut self, _time: f64, entry_maps: &[&EntryMap]) -> io::Result<()> {
+        self.rollover_files(entry_maps)
     }
 
-    /// Save new key-value entry to the right file.
+    /// Save new key-value entry to the right file, and add it to the index.
     fn save(&mut self, cat: &str, subkey: &str, entry: &Entry) -> io::Result<()> {
         if !self.files.contains_key(cat) {
-            let fp = self.create_fd(cat)?;
+            let fp = create_fd(&self.storepath, cat, &self.ymd_path, &self.tidy)?;
+            if self.history.use_index {
+                match self.history.index_update(&self.ymd_path, cat) {
+                    Ok(update) => { self.indexes.insert(cat.into(), update); }
+                    Err(e) => warn!("could not update history index for {}: {}", cat, e),
+                }
+            }
             self.files.insert(cat.into(), fp);
         }
         let fp = self.files.get_mut(cat).unwrap();
-        entry.to_file(subkey, fp)
+        entry.to_file(subkey, fp)?;
+        if let Some(index) = self.indexes.get_mut(cat) {
+            let end = fp.stream_position()?;
+            if let Err(e) = index.add(subkey, entry.time, end) {
+                // queries will index the rest of the file themselves
+                warn!("could not update history index for {}: {}", cat, e);
+                self.indexes.remove(cat);
+            }
+        }
+        Ok(())
     }
 
     /// Send history of a key to client.
-    fn query_history(&mut self, key: &str, from: f64, to: f64, send: &mut dyn FnMut(f64, &str)) {
+    fn query_history(&mut self, key: &str, from: f64, to: f64, limit: HistLimit,
+                     send: &mut dyn FnMut(f64, &str)) {
+        database::HistoryReader::query_history(&self.history, key, from, to, limit, send)
+    }
+
+    /// Send the entries of all days, skipping the copies of the latest values
+    /// that are written at rollover.
+    fn export(&mut self, send: &mut dyn FnMut(&str, &Entry) -> io::Result<()>) -> io::Result<()> {
+        let mut last_times = HashMap::<String, f64>::default();
+        for year in day_dirs(&self.storepath, 4)? {
+            for day in day_dirs(&self.storepath.join(&year), 5)? {
+                let daypath = self.storepath.join(&year).join(&day);
+                let mut files = read_dir(daypath)?.flatten()
+                    .filter(|dentry| dentry.file_type().map_or(false, |t| t.is_file()))
+                    .map(|dentry| dentry.path()).collect::<Vec<_>>();
+                files.sort_unstable();
+                for path in &files {
+                    // an interrupted compression can leave both files
+                    if path.extension() == Some("gz".as_ref()) &&
+                        files.contains(&path.with_extension("")) {
+                        continue;
+                    }
+                    let catname = file_catname(path);
+                    let mut result = Ok(());
+                    Self::read_storefile(path, |parts| {
+                        let time = match parts[1].parse() {
+                            Ok(time) if result.is_ok() => time,
+                            _ => return,
+                        };
+                        let key = construct_key(&catname, parts[0]);
+                        if last_times.insert(key.clone(), time) != Some(time) {
+                            let value = if parts[3] == "-" { "" } else { parts[3] };
+                            result = send(&key, &Entry::from_stored(time, parts[2] == "-", value));
+                        }
+                    })?;
+                    result?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Import an entry into the store file of the day of its timestamp.
+    fn import(&mut self, key: &str, entry: &Entry) -> io::Result<()> {
+        let (catname, subkey) = split_key(key);
+        let day = day_path(to_timespec(entry.time));
+        let import = self.import.get_or_insert_with(Import::default);
+        if day > import.day {
+            // like at rollover, start each day with the latest values
+            import.files.clear();
+            for (catname, map) in &import.latest {
+                let mut fp = create_fd(&self.storepath, catname, &day, &self.tidy)?;
+                for (subkey, entry) in map {
+                    if !entry.expired {
+                        entry.to_file(subkey, &mut fp)?;
+                    }
+                }
+                import.files.insert(catname.clone(), fp);
+            }
+            import.day = day.clone();
+        }
+        if day == import.day {
+            if !import.files.contains_key(catname) {
+                let fp = create_fd(&self.storepath, catname, &day, &self.tidy)?;
+                import.files.insert(catname.into(), fp);
+            }
+            entry.to_file(subkey, import.files.get_mut(catname).unwrap())?;
+        } else {
+            // entries that are late for their day
+            entry.to_file(subkey, &mut create_fd(&self.storepath, catname, &day, &self.tidy)?)?;
+        }
+        let map = import.latest.entry_ref(catname).or_insert_with(HashMap::default);
+        if map.get(subkey).map_or(true, |latest| latest.time <= entry.time) {
+            map.insert(subkey.into(), entry.clone());
+        }
+        Ok(())
+    }
+
+    /// After an import, the latest imported day has the latest values, unless
+    /// the store already has later days.
+    fn shutdown(&mut self) -> io::Result<()> {
+        if let Some(import) = self.import.take() {
+            let lastday = fs::read_link(self.storepath.join("lastday")).ok();
+            if lastday.map_or(true, |day| import.day.as_str() > &*day.to_string_lossy()) {
+                self.set_lastday(&import.day);
+            }
+        }
+        self.flush_indexes();
+        Ok(())
+    }
+
+    /// History is read directly from the files, which only needs the path.
+    fn history_reader(&self) -> Option<Box<dyn database::HistoryReader>> {
+        Some(Box::new(self.history.clone()))
+    }
+
+    /// Old days are pruned directly in the files, which only needs the path.
+    fn history_pruner(&self) -> Option<Box<dyn database::HistoryPruner>> {
+        Some(Box::new(Pruner { storepath: self.storepath.clone(), tidy: self.tidy.clone() }))
+    }
+}
+
+impl database::HistoryReader for History {
+    /// Send history of a key to client.  Oldest-first queries send the entries
+    /// while reading, and stop once the limit is reached.  For newest-first
+    /// queries, the day directories are walked backwards, and the entries of
+    /// each day are collected first.
+    fn query_history(&self, key: &str, from: f64, to: f64, limit: HistLimit,
+                     send: &mut dyn FnMut(f64, &str)) {
         let (catname, subkey) = split_key(key);
-        let paths = if from >= self.midnights.0 {
-            vec![self.ymd_path.clone()]
+        let thisday = thisday();
+        let mut paths = if from >= to_timefloat(thisday) {
+            vec![day_path(thisday)]
         } else {
             all_days(from, to)
         };
+        if limit.newest_first {
+            paths.reverse();
+        }
+        let mut sender = LimitedSender::new(limit, send);
         for path in paths {
-            if let Err(e) = self.read_history(&path, catname, subkey, from, to, send) {
+            let mut values = Vec::new();
+            let result = if limit.newest_first {
+                self.read_history(&path, catname, subkey, from, to, &mut |time, val| {
+                    values.push((time, val.to_string()));
+                    true
+                })
+            } else {
+                self.read_history(&path, catname, subkey, from, to,
+                                  &mut |time, val| sender.send(time, val))
+            };
+            if let Err(e) = result {
                 warn!("could not read histfile for {}/{}: {}", path, catname, e);
             }
+            if !sender.send_chunk(values) {
+                break;
+            }
         }
     }
 }
@@ -188,111 +711,843 @@ impl database::Store for Store {
 impl Store {
     /// Load keys from a single file for category "catname".
     fn load_one_file(&self, filename: &Path) -> io::Result<HashMap<String, Entry>> {
-        let fp = File::open(filename)?;
         let mut map = HashMap::default();
-        Self::read_storefile(fp, |parts| {
-            let subkey = parts[0];
-            if parts[2] == "+" {
-                // value is non-expiring: we can take it as valid
-                if let Ok(v) = parts[1].parse() {
-                    map.insert(subkey.into(), Entry::new(v, 0., parts[3]));
-                }
-            } else if parts[3] != "-" {
-                // value was expiring but is not empty: take it as expired
-                if let Ok(v) = parts[1].parse() {
-                    map.insert(subkey.into(), Entry::new(v, 0., parts[3]).expired());
-                }
-            } else if let Some(entry) = map.get_mut(subkey) {
-                // value is empty: be sure to mark any current value as expired
-                entry.expired = true;
-            }
-        });
+        Self::read_storefile(filename, |parts| apply_stored(&mut map, parts[0], &parts))?;
         Ok(map)
     }
 
     /// Set the "lastday" symlink to the latest yyyy/mm-dd directory.
-    fn set_lastday(&self) {
+    fn set_lastday(&self, ymd_path: &str) {
         let path = self.storepath.join("lastday");
         let _ = remove_file(&path);
-        if let Err(e) = symlink(&self.ymd_path, &path) {
+        if let Err(e) = symlink(ymd_path, &path) {
             warn!("could not set \"lastday\" symlink: {}", e);
         }
     }
 
     /// Roll over all store files after midnight has passed.
-    fn rollover(&mut self, entry_map: &mut EntryMap) -> io::Result<()> {
+    fn rollover_files(&mut self, entry_maps: &[&EntryMap]) -> io::Result<()> {
         info!("midnight passed, rolling over data files...");
         let thisday = thisday();
         self.midnights = (to_timefloat(thisday),
                           to_timefloat(thisday + Duration::days(1)));
         self.ymd_path = day_path(thisday);
+        self.flush_indexes();
+        self.indexes.clear();
         let old_files = mem::take(&mut self.files);
         for (catname, fp) in old_files {
             drop(fp);
-            let submap = entry_map.get(&catname).unwrap();
-            let mut new_fp = self.create_fd(&catname)?;
+            let submap = entry_maps.iter().find_map(|map| map.get(&catname)).unwrap();
+            let mut new_fp = create_fd(&self.storepath, &catname, &self.ymd_path, &self.tidy)?;
             for (subkey, entry) in submap {
                 if !entry.expired {
                     entry.to_file(subkey, &mut new_fp)?;
                 }
             }
         }
-        self.set_lastday();
+        self.set_lastday(&self.ymd_path);
+        self.compress_old_days();
         Ok(())
     }
 
-    /// Create a new file for a category.
-    fn create_fd(&self, catname: &str) -> io::Result<File> {
-        let safe_catname = catname.replace('/', "-");
-        let subpath = self.storepath.join(&self.ymd_path);
-        let linkfile = self.storepath.join(&safe_catname).join(&self.ymd_path);
-        ensure_dir(&subpath)?;
-        let file = subpath.join(safe_catname);
-        let mut fp = OpenOptions::new().create(true).append(true).open(&file)?;
-        if fp.seek(SeekFrom::Current(0))? == 0 {
-            fp.write_all(b"# NICOS cache store file v2\n")?;
+    /// Write the pending records of all index updates.
+    fn flush_indexes(&mut self) {
+        for (catname, index) in &mut self.indexes {
+            if let Err(e) = index.flush() {
+                warn!("could not update history index for {}: {}", catname, e);
+            }
         }
-        ensure_dir(linkfile.parent().unwrap())?;
-        if !linkfile.is_file() {
-            hard_link(file, linkfile)?;
+    }
+
+    /// Start compressing the store files of old days in the background, if
+    /// enabled and not already running.
+    fn compress_old_days(&mut self) {
+        let days = match self.compress_after {
+            Some(days) => days.max(1),
+            None => return,
+        };
+        if self.compressor.as_ref().map_or(false, |thread| !thread.is_finished()) {
+            return;
+        }
+        let last_day = day_path(thisday() - Duration::days(days.into()));
+        let storepath = self.storepath.clone();
+        let tidy = self.tidy.clone();
+        self.compressor = Some(thread::spawn(move || {
+            let _tidy = tidy.lock();
+            if let Err(e) = compress_days(&storepath, &last_day) {
+                warn!("could not compress old store files: {}", e);
+            }
+        }));
+    }
+
+    /// Read a store file, which can be compressed, and call the closure for
+    /// each entry.
+    fn read_storefile<F: FnMut(Vec<&str>)>(path: &Path, mut f: F) -> io::Result<()> {
+        let fp = File::open(path)?;
+        if path.extension() == Some("gz".as_ref()) {
+            Self::read_lines(GzDecoder::new(fp), 0, |_, _, parts| { f(parts); true });
+        } else {
+            Self::read_lines(fp, 0, |_, _, parts| { f(parts); true });
         }
-        Ok(fp)
+        Ok(())
     }
 
-    /// Read history for a given subkey from a file.
+    /// Read the part of a store file between two offsets and call the closure
+    /// for each entry, together with the offset and length of its line.
+    ///
+    /// Returns the offset after the last complete line that was read.
+    pub fn read_storefile_at<R, F>(mut fp: R, start: u64, end: u64, mut f: F) -> io::Result<u64>
+    where R: Read + Seek, F: FnMut(u64, u64, Vec<&str>)
+    {
+        fp.seek(SeekFrom::Start(start))?;
+        Ok(Self::read_lines(fp.take(end - start), start, |offset, n, parts| {
+            f(offset, n, parts);
+            true
+        }))
+    }
+
+    /// Read lines from a store file, starting at the given offset, and call
+    /// the closure for each entry, until it returns false.
+    ///
+    /// Returns the offset after the last line that was read.
+    fn read_lines<R, F>(fp: R, start: u64, mut f: F) -> u64
+    where R: Read, F: FnMut(u64, u64, Vec<&str>) -> bool
+    {
+        let mut reader = BufReader::new(fp);
+        let mut line = String::new();
+        let mut offset = start;
+        while let Ok(n) = reader.read_line(&mut line) {
+            if n == 0 || !line.ends_with('\n') {
+                // a line without newline is incomplete, either because it is
+                // being written or because writing it was interrupted
+                break;
+            }
+            let value;
+            let mut parts = line.trim().split('\t').collect::<Vec<_>>();
+            if parts.len() == 5 && parts[4] == ESCAPED_MARK {
+                value = unescape(parts[3]);
+                parts[3] = &value;
+                parts.truncate(4);
+            }
+            offset += n as u64;
+            if parts.len() == 4 && !f(offset - n as u64, n as u64, parts) {
+                break;
+            }
+            line.clear();
+        }
+        offset
+    }
+}
+
+impl History {
+    /// Read history for a given subkey from a file, until the closure returns
+    /// false.
     fn read_history<F>(&self, path: &str, catname: &str, subkey: &str,
                        from: f64, to: f64, send: &mut F) -> io::Result<()>
-    where F: FnMut(f64, &str) + ?Sized
+    where F: FnMut(f64, &str) -> bool + ?Sized
     {
         let catname = catname.replace('/', "-");
-        let path = self.storepath.join(path).join(catname);
-        if path.is_file() {
-            let fp = File::open(path)?;
-            Self::read_storefile(fp, |parts| {
-                if parts[0] == subkey {
+        let daypath = self.storepath.join(path);
+        let path = daypath.join(&catname);
+        let wanted = Cell::new(true);
+        let mut send_matching = |_, _, parts: Vec<&str>| {
+            if parts[0] == subkey {
+                let time = parts[1].parse().unwrap_or(0.);
+                if from <= time && time <= to {
+                    wanted.set(send(time, if parts[3] == "-" { "" } else { parts[3] }));
+                }
+            }
+            wanted.get()
+        };
+        let mut fp = match File::open(&path) {
+            Ok(fp) => fp,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // compressed files are not indexed
+                let gz_path = daypath.join(format!("{}{}", catname, COMPRESSED_EXT));
+                if gz_path.is_file() {
+                    let fp = GzDecoder::new(File::open(&gz_path)?);
+                    Store::read_lines(fp, 0, send_matching);
+                }
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        if self.use_index {
+            match self.update_index(&daypath, &catname, &path) {
+                Ok(index) => {
+                    for (start, end) in index.ranges(subkey, from, to) {
+                        fp.seek(SeekFrom::Start(start))?;
+                        Store::read_lines((&fp).take(end - start), start, &mut send_matching);
+                        if !wanted.get() {
+                            break;
+                        }
+                    }
+                    return Ok(());
+                }
+                Err(e) => warn!("could not update history index for {}: {}", path.display(), e),
+            }
+        }
+        Store::read_lines(fp, 0, send_matching);
+        Ok(())
+    }
+
+    /// Load the history index for a store file, after adding all entries that
+    /// have been written to the store file since the index was last updated.
+    ///
+    /// For older days, this builds the index on first use.  For the current
+    /// day, the store updates the index while saving, so that only the last
+    /// few entries have to be added here.
+    fn update_index(&self, daypath: &Path, catname: &str, path: &Path) -> io::Result<FileIndex> {
+        let indexpath = daypath.join(INDEX_DIR).join(catname);
+        let mut index = FileIndex::load(&indexpath)?;
+        let len = path.metadata()?.len();
+        if len < index.covered {
+            // the store file has been replaced: start over
+            remove_file(&indexpath)?;
+            index = FileIndex::default();
+        }
+        if len > index.covered {
+            let mut new = FileIndex::default();
+            let covered = Store::read_storefile_at(
+                File::open(path)?, index.covered, len, |offset, n, parts| {
                     let time = parts[1].parse().unwrap_or(0.);
-                    if from <= time && time <= to {
-                        send(time, if parts[3] == "-" { "" } else { parts[3] });
+                    new.add(parts[0], index_bucket(time), offset, offset + n);
+                })?;
+            if covered > index.covered {
+                new.append_to(&indexpath, covered)?;
+                for (subkey, buckets) in &new.ranges {
+                    for (&bucket, &(start, end)) in buckets {
+                        index.add(subkey, bucket, start, end);
                     }
                 }
-            });
+                index.covered = covered;
+            }
+        }
+        Ok(index)
+    }
+
+    /// Bring the index of a store file up to date, and prepare updating it
+    /// while saving further entries.
+    fn index_update(&self, ymd_path: &str, catname: &str) -> io::Result<IndexUpdate> {
+        let catname = catname.replace('/', "-");
+        let daypath = self.storepath.join(ymd_path);
+        let index = self.update_index(&daypath, &catname, &daypath.join(&catname))?;
+        Ok(IndexUpdate { indexpath: daypath.join(INDEX_DIR).join(catname),
+                         covered: index.covered,
+                         pending: FileIndex::default(),
+                         npending: 0 })
+    }
+}
+
+impl database::HistoryPruner for Pruner {
+    /// Remove or thin out the store files of whole days.  The latest day is
+    /// never touched, since it starts with the latest values.
+    fn prune(&mut self, retention: &Retention, now: f64) -> io::Result<()> {
+        let _tidy = self.tidy.lock();
+        let mut days = Vec::new();
+        for year in day_dirs(&self.storepath, 4)? {
+            for day in day_dirs(&self.storepath.join(&year), 5)? {
+                days.push(format!("{}/{}", year, day));
+            }
+        }
+        let latest_year = match days.pop() {
+            Some(day) => day[..4].to_string(),
+            None => return Ok(()),
+        };
+        let (mut nremoved, mut nthinned) = (0, 0);
+        for day in days {
+            let (_, day_end) = day_bounds(&day);
+            let daypath = self.storepath.join(&day);
+            let mut nleft = 0;
+            for dentry in read_dir(&daypath)?.flatten() {
+                let name = match dentry.file_name().into_string() {
+                    Ok(name) if dentry.file_type().map_or(false, |t| t.is_file()) &&
+                        !name.starts_with('.') => name,
+                    _ => continue,
+                };
+                match retention.rule_for(&file_catname(&dentry.path())) {
+                    Some(rule) if rule.keep_before(now).map_or(false, |t| day_end <= t) => {
+                        remove_file(dentry.path())?;
+                        let _ = remove_file(category_link(&self.storepath, &day, &name));
+                        let _ = remove_file(daypath.join(INDEX_DIR).join(&name));
+                        nremoved += 1;
+                    }
+                    Some(rule) if rule.thins() && day_end <= rule.raw_before(now) => {
+                        if self.thin_file(&day, &name)? {
+                            nthinned += 1;
+                        }
+                        nleft += 1;
+                    }
+                    _ => nleft += 1,
+                }
+            }
+            if nleft == 0 {
+                remove_dir_all(&daypath)?;
+                let _ = fs::remove_dir(daypath.parent().unwrap());
+            }
+        }
+        // remove link directories of past years that have become empty; the
+        // category directory then as well, if nothing is left
+        for catname in category_dirs(&self.storepath)? {
+            let catpath = self.storepath.join(catname);
+            for year in day_dirs(&catpath, 4)? {
+                if year < latest_year {
+                    let _ = fs::remove_dir(catpath.join(year));
+                }
+            }
+            let _ = fs::remove_dir(catpath);
+        }
+        if nremoved + nthinned > 0 {
+            info!("pruned history: removed {} and thinned out {} store files",
+                  nremoved, nthinned);
         }
         Ok(())
     }
+}
 
-    /// Read a store file and call the closure for each entry.
-    fn read_storefile<F: FnMut(Vec<&str>)>(fp: File, mut f: F) {
-        let mut reader = BufReader::new(fp);
-        let mut line = String::new();
-        while let Ok(n) = reader.read_line(&mut line) {
-            if n == 0 {
-                break;
+impl Pruner {
+    /// Thin out a store file to the last value of each subkey, unless that has
+    /// already been done.  Returns whether the file was changed.
+    fn thin_file(&self, day: &str, name: &str) -> io::Result<bool> {
+        let path = self.storepath.join(day).join(name);
+        let compressed = name.ends_with(COMPRESSED_EXT);
+        let reader: Box<dyn BufRead> = if compressed {
+            Box::new(BufReader::new(GzDecoder::new(File::open(&path)?)))
+        } else {
+            Box::new(BufReader::new(File::open(&path)?))
+        };
+        let mut last = HashMap::<String, (f64, String)>::default();
+        for line in reader.lines() {
+            let line = line?;
+            if line == THINNED_MARK {
+                return Ok(false);
+            }
+            let mut parts = line.split('\t');
+            if let (Some(subkey), Some(Ok(time))) = (parts.next(), parts.next().map(str::parse)) {
+                if !line.starts_with('#') && last.get(subkey).map_or(true, |e| e.0 <= time) {
+                    last.insert(subkey.into(), (time, line));
+                }
+            }
+        }
+        let mut lines = last.into_values().collect::<Vec<_>>();
+        lines.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+        let mut content = format!("{}\n{}\n", STORE_HEADER, THINNED_MARK);
+        for (_, line) in lines {
+            content.push_str(&line);
+            content.push('\n');
+        }
+
+        let tmp_path = self.storepath.join(".prune.tmp");
+        let mut fp = File::create(&tmp_path)?;
+        if compressed {
+            let mut encoder = GzEncoder::new(fp, Compression::default());
+            encoder.write_all(content.as_bytes())?;
+            fp = encoder.finish()?;
+        } else {
+            fp.write_all(content.as_bytes())?;
+        }
+        fp.sync_all()?;
+        rename(tmp_path, &path)?;
+        let linkfile = category_link(&self.storepath, day, name);
+        let _ = remove_file(&linkfile);
+        ensure_dir(linkfile.parent().unwrap())?;
+        hard_link(&path, linkfile)?;
+        let _ = remove_file(self.storepath.join(day).join(INDEX_DIR).join(name));
+        Ok(true)
+    }
+}
+
+/// Checks the files of a flat-file store, and repairs problems if requested.
+///
+/// The store must not be in use while it is checked.
+pub struct Fsck {
+    /// Root path for cache file storage.
+    storepath:    PathBuf,
+    /// Repair problems where possible?
+    repair:       bool,
+    /// Number of problems found.
+    problems:     usize,
+    /// Number of problems repaired.
+    repaired:     usize,
+}
+
+impl Fsck {
+    pub fn new(storepath: PathBuf, repair: bool) -> Fsck {
+        Fsck { storepath, repair, problems: 0, repaired: 0 }
+    }
+
+    /// Check the whole store and return the number of problems that remain.
+    pub fn run(mut self) -> io::Result<usize> {
+        self.check_category_links()?;
+        let mut days = Vec::new();
+        for year in day_dirs(&self.storepath, 4)? {
+            for day in day_dirs(&self.storepath.join(&year), 5)? {
+                days.push(format!("{}/{}", year, day));
+            }
+        }
+        for day in &days {
+            self.check_day(day)?;
+        }
+        if let Some(latest) = days.last() {
+            self.check_lastday(latest)?;
+        }
+        info!("checked {} days: {} problems found, {} repaired",
+              days.len(), self.problems, self.repaired);
+        Ok(self.problems - self.repaired)
+    }
+
+    /// Report a problem, and return whether it should be repaired.
+    fn problem(&mut self, path: &Path, what: &str, repairable: bool) -> bool {
+        warn!("{}: {}", path.display(), what);
+        self.problems += 1;
+        if repairable && self.repair {
+            self.repaired += 1;
+        }
+        repairable && self.repair
+    }
+
+    /// Check the lines of all store files of a day, and their hard links.
+    fn check_day(&mut self, day: &str) -> io::Result<()> {
+        let daypath = self.storepath.join(day);
+        let mut files = read_dir(&daypath)?.flatten()
+            .filter(|dentry| dentry.file_type().map_or(false, |t| t.is_file()))
+            .filter_map(|dentry| dentry.file_name().into_string().ok())
+            .collect::<Vec<_>>();
+        files.sort_unstable();
+        for catname in files {
+            self.check_file(day, &catname)?;
+            let file = daypath.join(&catname);
+            let linkfile = category_link(&self.storepath, day, &catname);
+            let same_file = match (file.metadata(), linkfile.metadata()) {
+                (Ok(m1), Ok(m2)) => Some((m1.dev(), m1.ino()) == (m2.dev(), m2.ino())),
+                _ => None,
+            };
+            let problem = match same_file {
+                Some(true) => continue,
+                Some(false) => "category link is not the same file",
+                None => "category link is missing",
+            };
+            if self.problem(&file, problem, true) {
+                let _ = remove_file(&linkfile);
+                ensure_dir(linkfile.parent().unwrap())?;
+                hard_link(&file, &linkfile)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the entries of a single store file.
+    ///
+    /// When repairing, invalid lines are moved to the lost+found directory.
+    /// Compressed files are only checked.
+    fn check_file(&mut self, day: &str, catname: &str) -> io::Result<()> {
+        let path = self.storepath.join(day).join(catname);
+        let (midnight, next_midnight) = day_bounds(day);
+        let compressed = catname.ends_with(COMPRESSED_EXT);
+        let mut reader: Box<dyn BufRead> = if compressed {
+            Box::new(BufReader::new(GzDecoder::new(File::open(&path)?)))
+        } else {
+            Box::new(BufReader::new(File::open(&path)?))
+        };
+        let mut good = Vec::new();
+        let mut lost = Vec::new();
+        let mut line = Vec::new();
+        let mut lineno = 0;
+        let mut last_time = f64::NEG_INFINITY;
+        let mut at_start = true;
+        let mut backwards = 0;
+        let mut future = 0;
+        loop {
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(e) => {
+                    self.problem(&path, &format!("read error: {}", e), false);
+                    break;
+                }
             }
-            let parts = line.trim().split('\t').collect::<Vec<_>>();
-            if parts.len() == 4 {
-                f(parts);
+            lineno += 1;
+            let result = match str::from_utf8(&line) {
+                _ if !line.ends_with(b"\n") => Err("incomplete line"),
+                Err(_) => Err("invalid UTF-8"),
+                Ok(text) if lineno == 1 => if text.trim_end() == STORE_HEADER {
+                    Ok(None)
+                } else {
+                    self.problem(&path, "missing v2 header", !compressed);
+                    check_line(text)
+                },
+                Ok(text) if text.starts_with('#') => Ok(None),
+                Ok(text) => check_line(text),
+            };
+            match result {
+                Ok(Some(time)) => {
+                    // the latest values are copied to the start of each day
+                    if !(at_start && time < midnight) {
+                        at_start = false;
+                        if time < last_time {
+                            backwards += 1;
+                        }
+                        if time >= next_midnight {
+                            future += 1;
+                        }
+                        last_time = last_time.max(time);
+                    }
+                    good.extend_from_slice(&line);
+                }
+                Ok(None) => good.extend_from_slice(&line),
+                Err(what) => {
+                    self.problem(&path, &format!("line {}: {}", lineno, what), !compressed);
+                    lost.extend_from_slice(&line);
+                }
             }
             line.clear();
         }
+        if backwards > 0 {
+            self.problem(&path, &format!("{} entries have times going backwards", backwards), false);
+        }
+        if future > 0 {
+            self.problem(&path, &format!("{} entries have times after this day", future), false);
+        }
+        if self.repair && !compressed && (!good.starts_with(STORE_HEADER.as_bytes()) || !lost.is_empty()) {
+            let mut header = STORE_HEADER.to_string() + "\n";
+            if good.starts_with(STORE_HEADER.as_bytes()) {
+                header.clear();
+            }
+            let lost_dir = self.storepath.join(LOST_DIR);
+            ensure_dir(&lost_dir)?;
+            if !lost.is_empty() {
+                OpenOptions::new().create(true).append(true)
+                                  .open(lost_dir.join(format!("{}-{}", day.replace('/', "-"),
+                                                              catname)))?
+                                  .write_all(&lost)?;
+            }
+            let tmp_path = lost_dir.join(".fsck.tmp");
+            let mut fp = File::create(&tmp_path)?;
+            fp.write_all(header.as_bytes())?;
+            fp.write_all(&good)?;
+            fp.sync_all()?;
+            rename(tmp_path, &path)?;
+            // offsets have changed, the index must be rebuilt
+            let _ = remove_file(self.storepath.join(day).join(INDEX_DIR).join(catname));
+        }
+        Ok(())
+    }
+
+    /// Check that each category link has a store file in the day directory.
+    fn check_category_links(&mut self) -> io::Result<()> {
+        for catname in category_dirs(&self.storepath)? {
+            let catpath = self.storepath.join(&catname);
+            for year in day_dirs(&catpath, 4)? {
+                // the category links are files, named like the day directories
+                let mut links = read_dir(catpath.join(&year))?.flatten()
+                    .filter(|dentry| dentry.file_type().map_or(false, |t| t.is_file()))
+                    .filter_map(|dentry| dentry.file_name().into_string().ok())
+                    .filter(|name| name.strip_suffix(COMPRESSED_EXT).unwrap_or(name).len() == 5)
+                    .collect::<Vec<_>>();
+                links.sort_unstable();
+                for link in links {
+                    let (day, ext) = link.split_at(5);
+                    let day = format!("{}/{}", year, day);
+                    let file = self.storepath.join(&day).join(format!("{}{}", catname, ext));
+                    if !file.exists() && self.problem(&file, "store file is missing, \
+                                                              but category link exists", true) {
+                        ensure_dir(file.parent().unwrap())?;
+                        hard_link(catpath.join(&year).join(&link), &file)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the lastday link points to the latest day.
+    fn check_lastday(&mut self, latest: &str) -> io::Result<()> {
+        let path = self.storepath.join("lastday");
+        let problem = match fs::read_link(&path) {
+            Ok(target) if target == Path::new(latest) => return Ok(()),
+            Ok(_) => "does not point to the latest day",
+            Err(_) => "is missing",
+        };
+        if self.problem(&path, problem, true) {
+            let _ = remove_file(&path);
+            symlink(latest, &path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Get the timestamps of the start and end of a day, given as YYYY/MM-DD.
+fn day_bounds(day: &str) -> (f64, f64) {
+    let parse = |range: std::ops::Range<usize>| day.get(range).and_then(|s| s.parse::<u32>().ok());
+    match (parse(0..4), parse(5..7), parse(8..10)) {
+        (Some(y), Some(m), Some(d)) => match Local.with_ymd_and_hms(y as i32, m, d, 0, 0, 0).earliest() {
+            Some(start) => (to_timefloat(start), to_timefloat(start + Duration::days(1))),
+            None => (f64::NEG_INFINITY, f64::INFINITY),
+        },
+        _ => (f64::NEG_INFINITY, f64::INFINITY),
+    }
+}
+
+/// Check the format of a store file line, and return its timestamp.
+fn check_line(line: &str) -> Result<Option<f64>, &'static str> {
+    let parts = line.trim_end_matches(&['\n', '\r'][..]).split('\t').collect::<Vec<_>>();
+    match parts.len() {
+        4 => (),
+        5 if parts[4] == ESCAPED_MARK => (),
+        _ => return Err("wrong number of fields"),
+    }
+    if parts[2] != "+" && parts[2] != "-" {
+        return Err("invalid TTL flag");
+    }
+    match parts[1].parse::<f64>() {
+        Ok(time) if time.is_finite() => Ok(Some(time)),
+        _ => Err("invalid timestamp"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn all_days_boundaries() {
+        let (midnight, _) = day_bounds("2020/01-01");
+        let hour = 3600.;
+        // a span of less than a day still covers both days if it crosses midnight
+        assert_eq!(all_days(midnight + 10. * hour, midnight + 25. * hour),
+                   ["2020/01-01", "2020/01-02"]);
+        assert_eq!(all_days(midnight + hour, midnight + 2. * hour), ["2020/01-01"]);
+        assert_eq!(all_days(midnight + hour, midnight + hour), ["2020/01-01"]);
+        assert_eq!(all_days(midnight - hour, midnight + 49. * hour),
+                   ["2019/12-31", "2020/01-01", "2020/01-02", "2020/01-03"]);
+    }
+
+    #[test]
+    fn history_index() {
+        let dir = TempDir::new("index");
+        let storepath = dir.path().to_path_buf();
+        let daypath = storepath.join("2020/01-01");
+        ensure_dir(&daypath).unwrap();
+        let mut fp = OpenOptions::new().create(true).append(true)
+                                       .open(daypath.join("cat")).unwrap();
+        let plain = History { storepath: storepath.clone(), use_index: false };
+        let indexed = History { storepath: storepath.clone(), use_index: true };
+        let query = |history: &History, subkey, from, to| {
+            let mut res = Vec::new();
+            history.read_history("2020/01-01", "cat", subkey, from, to, &mut |time, val: &str| {
+                res.push((time, val.to_string()));
+                true
+            }).unwrap();
+            res
+        };
+
+        // the second round checks incremental updates of the index
+        for round in 0..2 {
+            for i in 0..2000 {
+                let time = 1000. * round as f64 + (i as f64 * 1.7) % 1000.;
+                Entry::new(time, 0., &i.to_string()).to_file(&format!("k{}", i % 7), &mut fp)
+                                                    .unwrap();
+            }
+            for &(subkey, from, to) in &[("k0", 0., 5000.), ("k3", 100., 400.),
+                                         ("k6", 900., 1100.), ("k9", 0., 5000.)] {
+                let expected = query(&plain, subkey, from, to);
+                assert_eq!(query(&indexed, subkey, from, to), expected);
+                assert_eq!(expected.is_empty(), subkey == "k9");
+            }
+        }
+        assert!(daypath.join(INDEX_DIR).join("cat").is_file());
+    }
+
+    #[test]
+    fn index_on_save() {
+        use crate::database::{HistoryReader, Store as _};
+
+        let dir = TempDir::new("index-save");
+        let mut store = Store::new(dir.path().to_path_buf(), true, None);
+        let now = to_timefloat(thisday()) + 10.;
+        let indexpath = dir.path().join(&store.ymd_path).join(INDEX_DIR).join("cat-sub");
+        let path = dir.path().join(&store.ymd_path).join("cat-sub");
+        for i in 0..2 * INDEX_BATCH + 10 {
+            store.save("cat/sub", &format!("k{}", i % 3),
+                       &Entry::new(now + i as f64, 0., &i.to_string())).unwrap();
+        }
+        // the index is written in batches, without any query
+        let index = FileIndex::load(&indexpath).unwrap();
+        assert!(index.covered > 0 && index.covered < path.metadata().unwrap().len());
+        store.shutdown().unwrap();
+        let index = FileIndex::load(&indexpath).unwrap();
+        assert_eq!(index.covered, path.metadata().unwrap().len());
+
+        let query = |use_index| {
+            let history = History { storepath: dir.path().to_path_buf(), use_index };
+            let mut res = Vec::new();
+            history.query_history("cat/sub/k1", now + 50., now + 150., HistLimit::default(),
+                                  &mut |time, val| res.push((time, val.to_string())));
+            res
+        };
+        assert_eq!(query(true).len(), 33);
+        assert_eq!(query(true), query(false));
+    }
+
+    #[test]
+    fn compressed_days() {
+        use crate::database::{HistoryReader, Store as _};
+
+        let dir = TempDir::new("compress");
+        let storepath = dir.path().to_path_buf();
+        let mut store = Store::new(storepath.clone(), false, None);
+        let (midnight, _) = day_bounds("2020/01-01");
+        for i in 0..200 {
+            let time = midnight + i as f64 * 1000.;
+            store.import(&format!("cat/k{}", i % 3), &Entry::new(time, 0., &i.to_string()))
+                 .unwrap();
+        }
+        store.shutdown().unwrap();
+        let contents = |store: &mut Store| {
+            let mut exported = Vec::new();
+            store.export(&mut |key, entry| {
+                exported.push((key.to_string(), entry.time, entry.value.clone()));
+                Ok(())
+            }).unwrap();
+            let mut history = Vec::new();
+            store.history.query_history("cat/k1", midnight + 50000., midnight + 150000.,
+                                        HistLimit::default(),
+                                        &mut |time, value| history.push((time, value.to_string())));
+            (exported, history)
+        };
+        let before = contents(&mut store);
+        assert!(before.1.len() > 30);
+
+        compress_days(&storepath, "2020/01-02").unwrap();
+        assert!(storepath.join("2020/01-02/cat.gz").is_file());
+        assert!(storepath.join("cat/2020/01-02.gz").is_file());
+        assert!(!storepath.join("2020/01-02/cat").exists());
+        assert!(storepath.join("2020/01-03/cat").is_file());
+        assert_eq!(contents(&mut store), before);
+        assert_eq!(Fsck::new(storepath.clone(), false).run().unwrap(), 0);
+
+        // importing into a compressed day adds to its entries
+        let mut store = Store::new(storepath.clone(), false, None);
+        let time = midnight + 2. * 86400. - 50.;
+        store.import("cat/late", &Entry::new(time, 0., "late")).unwrap();
+        store.shutdown().unwrap();
+        assert!(storepath.join("2020/01-02/cat").is_file());
+        assert!(!storepath.join("2020/01-02/cat.gz").exists());
+        assert!(!storepath.join("cat/2020/01-02.gz").exists());
+        assert_eq!(fs::read_link(storepath.join("lastday")).unwrap(), Path::new("2020/01-03"));
+        let (exported, history) = contents(&mut store);
+        assert_eq!(exported.len(), before.0.len() + 1);
+        assert!(exported.contains(&("cat/late".into(), time, "late".into())));
+        assert_eq!(history, before.1);
+        let mut history = Vec::new();
+        store.history.query_history("cat/late", midnight, midnight + 200000.,
+                                    HistLimit::default(), &mut |time, _| history.push(time));
+        assert_eq!(history, [time]);
+        assert_eq!(Fsck::new(storepath.clone(), false).run().unwrap(), 0);
+    }
+
+    #[test]
+    fn history_limit() {
+        use crate::database::{HistoryReader, Store as _};
+
+        let dir = TempDir::new("limit");
+        let storepath = dir.path().to_path_buf();
+        let mut store = Store::new(storepath.clone(), false, None);
+        let (start, _) = day_bounds("2020/01-01");
+        for i in 0..24 {
+            store.import("cat/key", &Entry::new(start + i as f64 * 10800., 0., &i.to_string()))
+                 .unwrap();
+        }
+        store.shutdown().unwrap();
+        let query = |count, newest_first| {
+            let mut res = Vec::new();
+            store.history.query_history("cat/key", start, start + 3. * 86400.,
+                                        HistLimit { count, newest_first },
+                                        &mut |_, value| res.push(value.parse::<u32>().unwrap()));
+            res
+        };
+        assert_eq!(query(None, false), (0..24).collect::<Vec<_>>());
+        assert_eq!(query(Some(10), true), (14..24).rev().collect::<Vec<_>>());
+        assert_eq!(query(Some(10), false), (0..10).collect::<Vec<_>>());
+
+        // reading a day stops as soon as no more entries are wanted
+        for use_index in [false, true] {
+            let history = History { storepath: storepath.clone(), use_index };
+            let mut calls = 0;
+            history.read_history("2020/01-01", "cat", "key", start, start + 86400.,
+                                 &mut |_, _| { calls += 1; calls < 3 }).unwrap();
+            assert_eq!(calls, 3);
+        }
+    }
+
+    #[test]
+    fn prune_days() {
+        use crate::database::Store as _;
+        use crate::retention::Rule;
+
+        let dir = TempDir::new("prune");
+        let storepath = dir.path().to_path_buf();
+        let mut store = Store::new(storepath.clone(), false, None);
+        let (start, _) = day_bounds("2020/01-01");
+        let day = 86400.;
+        for i in 0..80 {
+            let entry = Entry::new(start + i as f64 * 10800., 0., &i.to_string());
+            for key in ["cat/k0", "cat/k1", "other/k"] {
+                store.import(key, &entry).unwrap();
+            }
+        }
+        store.shutdown().unwrap();
+
+        let retention = Retention::new(vec![Rule::parse("cat=2,daily=5").unwrap(),
+                                            Rule::parse("other=3").unwrap()]);
+        let mut pruner = store.history_pruner().unwrap();
+        pruner.prune(&retention, start + 10. * day + 3600.).unwrap();
+        assert!(!storepath.join("2020/01-01").exists());
+        assert!(!storepath.join("2020/01-05/cat").exists());
+        assert!(!storepath.join("cat/2020/01-05").exists());
+        assert!(!storepath.join("2020/01-07/other").exists());
+        assert!(storepath.join("2020/01-08/other").is_file());
+        assert_eq!(std::fs::read_to_string(storepath.join("2020/01-09/cat")).unwrap()
+                   .lines().count(), 1 + 2 + 8 * 2);
+        let thinned = std::fs::read_to_string(storepath.join("2020/01-06/cat")).unwrap();
+        let last = start + 47. * 10800.;
+        assert_eq!(thinned, format!("{}\n{}\nk0\t{}\t+\t47\nk1\t{}\t+\t47\n",
+                                    STORE_HEADER, THINNED_MARK, last, last));
+
+        pruner.prune(&retention, start + 10. * day + 3600.).unwrap();
+        assert_eq!(std::fs::read_to_string(storepath.join("2020/01-06/cat")).unwrap(), thinned);
+        assert_eq!(Fsck::new(storepath.clone(), false).run().unwrap(), 0);
+    }
+
+    #[test]
+    fn fsck_repair() {
+        let dir = TempDir::new("fsck");
+        let storepath = dir.path().to_path_buf();
+        let (midnight, _) = day_bounds("2020/01-01");
+        ensure_dir(storepath.join("2020/01-01")).unwrap();
+        let mut fp = File::create(storepath.join("2020/01-01/cat")).unwrap();
+        writeln!(fp, "{}", STORE_HEADER).unwrap();
+        Entry::new(midnight - 10., 0., "1").to_file("a", &mut fp).unwrap();
+        Entry::new(midnight + 10., 0., "2").to_file("a", &mut fp).unwrap();
+        fp.write_all(b"b\tnot a time\t-\t3\n").unwrap();
+        Entry::new(midnight + 20., 0., "4").to_file("b", &mut fp).unwrap();
+        fp.write_all(b"b\t").unwrap();
+        // only the category link exists for the second day
+        ensure_dir(storepath.join("dev/2020")).unwrap();
+        let mut fp = File::create(storepath.join("dev/2020/01-02")).unwrap();
+        writeln!(fp, "{}", STORE_HEADER).unwrap();
+
+        assert_eq!(Fsck::new(storepath.clone(), false).run().unwrap(), 5);
+        assert_eq!(Fsck::new(storepath.clone(), true).run().unwrap(), 0);
+        assert_eq!(Fsck::new(storepath.clone(), false).run().unwrap(), 0);
+
+        let content = std::fs::read_to_string(storepath.join("2020/01-01/cat")).unwrap();
+        assert_eq!(content.lines().count(), 4);
+        let lost = std::fs::read_to_string(storepath.join(LOST_DIR).join("2020-01-01-cat"))
+            .unwrap();
+        assert_eq!(lost, "b\tnot a time\t-\t3\nb\t");
+        assert!(storepath.join("2020/01-02/dev").is_file());
+        assert_eq!(fs::read_link(storepath.join("lastday")).unwrap(), Path::new("2020/01-02"));
     }
 }
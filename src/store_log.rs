@@ -0,0 +1,680 @@
+// -----------------------------------------------------------------------------
+// A Rust implementation of the NICOS cache server.
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// -----------------------------------------------------------------------------
+//
+//
+//! Log-structured database store.
+//!
+//! All entries are appended to a log that is split into segments.  A segment
+//! is sealed when it grows too large or too old, and its time range is recorded
+//! in the manifest.  At the same time, the latest values are compacted into a
+//! snapshot, so that loading needs to read only the snapshot and the segments
+//! written after it.  Sealed segments are kept for history queries, with an
+//! index of the file ranges for each key, until retention rules prune them.
+
+use std::fs::{self, File, OpenOptions, read_dir, remove_dir_all, remove_file, rename};
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use log::{info, warn};
+use hashbrown::HashMap;
+use mlzutil::fs::ensure_dir;
+use mlzutil::time::to_timespec;
+
+use crate::database::{self, EntryMap, HistLimit, LimitedSender};
+use crate::entry::{Entry, split_key, construct_key};
+use crate::retention::{Retention, Rule};
+use crate::store_flat::{Store as FlatStore, FileIndex, apply_stored, day_path, index_bucket};
+
+/// Subdirectory for the log segments.
+const SEGMENT_DIR: &str = "segments";
+/// File with the sequence number and time range of sealed segments.
+const MANIFEST: &str = "manifest";
+/// File with the compacted latest values.
+const SNAPSHOT: &str = "snapshot";
+/// Header of the snapshot, followed by the first segment it does not cover.
+const SNAPSHOT_HEADER: &str = "# NICOS cache snapshot v1, next segment ";
+
+/// Size after which a segment is sealed.
+const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+/// Time after which a segment is sealed, in seconds.
+const SEGMENT_SPAN: f64 = 86400.;
+
+fn segment_path(root: &Path, seq: u64) -> PathBuf {
+    root.join(SEGMENT_DIR).join(format!("{:08}.log", seq))
+}
+
+fn index_path(root: &Path, seq: u64) -> PathBuf {
+    root.join(SEGMENT_DIR).join(format!("{:08}.idx", seq))
+}
+
+/// Get the sequence numbers of all existing segments, in order.
+fn list_segments(root: &Path) -> io::Result<Vec<u64>> {
+    let mut res = Vec::new();
+    for dentry in read_dir(root.join(SEGMENT_DIR))?.flatten() {
+        let path = dentry.path();
+        if path.extension().map_or(false, |ext| ext == "log") {
+            if let Some(seq) = path.file_stem().and_then(|s| s.to_str()?.parse().ok()) {
+                res.push(seq);
+            }
+        }
+    }
+    res.sort_unstable();
+    Ok(res)
+}
+
+/// A sealed segment, as recorded in the manifest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Sealed {
+    /// Minimum and maximum time of the entries in the segment.
+    times:  (f64, f64),
+    /// Retention state the segment has been pruned to, or zero.
+    pruned: u64,
+}
+
+/// Read the sealed segments.  The manifest is only appended to, so later
+/// records of a segment replace earlier ones.
+fn read_manifest(root: &Path) -> BTreeMap<u64, Sealed> {
+    let mut res = BTreeMap::new();
+    if let Ok(fp) = File::open(root.join(MANIFEST)) {
+        for line in BufReader::new(fp).lines().map_while(Result::ok) {
+            let parts = line.split('\t').collect::<Vec<_>>();
+            if let [seq, min, max, ..] = parts[..] {
+                let pruned = parts.get(3).map_or(Ok(0), |p| p.parse());
+                if let (Ok(seq), Ok(min), Ok(max), Ok(pruned)) =
+                    (seq.parse(), min.parse(), max.parse(), pruned) {
+                    res.insert(seq, Sealed { times: (min, max), pruned });
+                }
+            }
+        }
+    }
+    res
+}
+
+/// Append a record for a sealed segment to the manifest.
+fn append_manifest(root: &Path, seq: u64, sealed: Sealed) -> io::Result<()> {
+    let mut fp = OpenOptions::new().create(true).append(true).open(root.join(MANIFEST))?;
+    let line = if sealed.pruned == 0 {
+        format!("{}\t{}\t{}\n", seq, sealed.times.0, sealed.times.1)
+    } else {
+        format!("{}\t{}\t{}\t{}\n", seq, sealed.times.0, sealed.times.1, sealed.pruned)
+    };
+    fp.write_all(line.as_bytes())
+}
+
+/// Read a segment and call the closure for each entry, with its full key,
+/// together with the offset and length of its line.
+///
+/// Returns the length of the segment up to the last complete line.
+fn read_segment<F: FnMut(u64, u64, Vec<&str>)>(root: &Path, seq: u64, f: F) -> io::Result<u64> {
+    let fp = File::open(segment_path(root, seq))?;
+    FlatStore::read_storefile_at(fp, 0, u64::MAX, f)
+}
+
+/// Currently written segment.
+struct Segment {
+    seq:      u64,
+    file:     File,
+    /// Length of the segment file.
+    len:      u64,
+    /// Minimum and maximum time of the entries in the segment.
+    times:    (f64, f64),
+    /// Time of the first entry, from which the age of the segment is counted.
+    started:  f64,
+    /// File ranges of the entries, by key and time bucket.
+    index:    FileIndex,
+}
+
+/// Represents the log-structured backend store.
+pub struct Store {
+    /// Root path for the store files.
+    root:     PathBuf,
+    /// Segment that is currently written, if any.
+    active:   Option<Segment>,
+    /// Sequence number for the next new segment.
+    next_seq: u64,
+    /// Reader for the history in the segments.
+    history:  History,
+}
+
+/// Read-only access to the history in the segments, independent of the store
+/// that writes them.
+#[derive(Clone)]
+pub struct History {
+    /// Root path for the store files.
+    root:     PathBuf,
+}
+
+impl Store {
+    pub fn new(root: PathBuf) -> Store {
+        Store { history: History { root: root.clone() }, root, active: None, next_seq: 0 }
+    }
+
+    /// Apply a logged entry to the entry map.
+    fn apply(entry_map: &mut EntryMap, parts: Vec<&str>) {
+        let (catname, subkey) = split_key(parts[0]);
+        let map = entry_map.entry_ref(catname).or_insert_with(HashMap::default);
+        apply_stored(map, subkey, &parts);
+    }
+
+    /// Read the snapshot into the entry map, and return the first segment that
+    /// is not covered by it.
+    fn load_snapshot(&self, entry_map: &mut EntryMap) -> io::Result<u64> {
+        let path = self.root.join(SNAPSHOT);
+        if !path.is_file() {
+            return Ok(0);
+        }
+        let mut header = String::new();
+        BufReader::new(File::open(&path)?).read_line(&mut header)?;
+        let next_seq = header.strip_prefix(SNAPSHOT_HEADER)
+                             .and_then(|seq| seq.trim().parse().ok())
+                             .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                                                           "invalid snapshot header"))?;
+        FlatStore::read_storefile_at(File::open(&path)?, 0, u64::MAX,
+                                     |_, _, parts| Self::apply(entry_map, parts))?;
+        Ok(next_seq)
+    }
+
+    /// Write a new snapshot of the latest values, covering all segments before
+    /// the next one.
+    fn write_snapshot(&self, entry_maps: &[&EntryMap]) -> io::Result<()> {
+        let tmp_path = self.root.join(format!("{}.tmp", SNAPSHOT));
+        let mut fp = BufWriter::new(File::create(&tmp_path)?);
+        writeln!(fp, "{}{}", SNAPSHOT_HEADER, self.next_seq)?;
+        for (catname, map) in entry_maps.iter().flat_map(|map| map.iter()) {
+            for (subkey, entry) in map {
+                if !entry.expired {
+                    entry.to_file(&construct_key(catname, subkey), &mut fp)?;
+                }
+            }
+        }
+        fp.into_inner()?.sync_all()?;
+        rename(tmp_path, self.root.join(SNAPSHOT))
+    }
+
+    /// Seal the active segment, writing its index and recording its time
+    /// range in the manifest.
+    fn seal(&mut self) -> io::Result<()> {
+        if let Some(segment) = self.active.take() {
+            segment.file.sync_all()?;
+            // without the index, history queries read the whole segment
+            let _ = remove_file(index_path(&self.root, segment.seq));
+            if let Err(e) = segment.index.append_to(&index_path(&self.root, segment.seq),
+                                                    segment.len) {
+                warn!("could not write index of log segment {}: {}", segment.seq, e);
+            }
+            append_manifest(&self.root, segment.seq, Sealed { times: segment.times, pruned: 0 })?;
+            self.next_seq = segment.seq + 1;
+        }
+        Ok(())
+    }
+}
+
+impl database::Store for Store {
+    /// Clear DB by removing all segments.
+    fn clear(&mut self) -> io::Result<()> {
+        self.active = None;
+        self.next_seq = 0;
+        if self.root.is_dir() {
+            remove_dir_all(&self.root)?;
+        }
+        ensure_dir(self.root.join(SEGMENT_DIR))
+    }
+
+    /// Load the latest DB entries from the snapshot and the newer segments.
+    fn load_latest(&mut self, entry_map: &mut EntryMap) -> io::Result<()> {
+        ensure_dir(self.root.join(SEGMENT_DIR))?;
+        let first_seq = self.load_snapshot(entry_map)?;
+        let sealed = read_manifest(&self.root);
+        let segments = list_segments(&self.root)?;
+        let mut nsegments = 0;
+        for &seq in segments.iter().filter(|&&seq| seq >= first_seq) {
+            let mut times = (f64::INFINITY, f64::NEG_INFINITY);
+            let mut started = None;
+            let mut index = FileIndex::default();
+            let len = read_segment(&self.root, seq, |offset, n, parts| {
+                if let Ok(time) = parts[1].parse::<f64>() {
+                    times = (times.0.min(time), times.1.max(time));
+                    started.get_or_insert(time);
+                    index.add(parts[0], index_bucket(time), offset, offset + n);
+                }
+                Self::apply(entry_map, parts);
+            })?;
+            nsegments += 1;
+            if sealed.contains_key(&seq) {
+                continue;
+            }
+            // continue writing to an unsealed segment, after cutting off an
+            // incomplete last line
+            self.seal()?;
+            let file = OpenOptions::new().append(true).open(segment_path(&self.root, seq))?;
+            file.set_len(len)?;
+            self.active = Some(Segment { seq, file, len, times, index,
+                                         started: started.unwrap_or(f64::INFINITY) });
+        }
+        if let Some(&last) = segments.last() {
+            self.next_seq = self.next_seq.max(last + 1);
+        }
+        info!("db: read {} entries from snapshot and {} segments",
+              entry_map.values().map(|map| map.len()).sum::<usize>(), nsegments);
+        Ok(())
+    }
+
+    /// The active segment must be sealed when it is too large, or when the new
+    /// entry is too much newer than its first one.
+    fn needs_rollover(&self, time: f64) -> bool {
+        self.active.as_ref().map_or(false, |segment| {
+            segment.len >= SEGMENT_SIZE || time >= segment.started + SEGMENT_SPAN
+        })
+    }
+
+    /// Seal the active segment and compact the latest values into a new snapshot.
+    fn rollover(&mut self, _time: f64, entry_maps: &[&EntryMap]) -> io::Result<()> {
+        self.seal()?;
+        self.write_snapshot(entry_maps)
+    }
+
+    /// Append a new key-value entry to the active segment.
+    fn save(&mut self, catname: &str, subkey: &str, entry: &Entry) -> io::Result<()> {
+        if self.active.is_none() {
+            let seq = self.next_seq;
+            let file = OpenOptions::new().create(true).append(true)
+                                         .open(segment_path(&self.root, seq))?;
+            self.active = Some(Segment { seq, file, len: 0,
+                                         times: (f64::INFINITY, f64::NEG_INFINITY),
+                                         started: entry.time, index: FileIndex::default() });
+            self.next_seq += 1;
+        }
+        let segment = self.active.as_mut().unwrap();
+        let key = construct_key(catname, subkey);
+        let mut line = Vec::new();
+        entry.to_file(&key, &mut line)?;
+        segment.file.write_all(&line)?;
+        segment.index.add(&key, index_bucket(entry.time), segment.len,
+                          segment.len + line.len() as u64);
+        segment.len += line.len() as u64;
+        segment.times = (segment.times.0.min(entry.time), segment.times.1.max(entry.time));
+        Ok(())
+    }
+
+    /// Send history of a key to client.
+    fn query_history(&mut self, key: &str, from: f64, to: f64, limit: HistLimit,
+                     send: &mut dyn FnMut(f64, &str)) {
+        database::HistoryReader::query_history(&self.history, key, from, to, limit, send)
+    }
+
+    /// Send the entries of all segments.
+    fn export(&mut self, send: &mut dyn FnMut(&str, &Entry) -> io::Result<()>) -> io::Result<()> {
+        for seq in list_segments(&self.root)? {
+            let mut result = Ok(());
+            read_segment(&self.root, seq, |_, _, parts| {
+                if let (Ok(()), Ok(time)) = (&result, parts[1].parse()) {
+                    let value = if parts[3] == "-" { "" } else { parts[3] };
+                    result = send(parts[0], &Entry::from_stored(time, parts[2] == "-", value));
+                }
+            })?;
+            result?;
+        }
+        Ok(())
+    }
+
+    /// History is read directly from the segments, which only needs the path.
+    fn history_reader(&self) -> Option<Box<dyn database::HistoryReader>> {
+        Some(Box::new(self.history.clone()))
+    }
+
+    /// Sealed segments are pruned directly, which only needs the path.
+    fn history_pruner(&self) -> Option<Box<dyn database::HistoryPruner>> {
+        Some(Box::new(Pruner { root: self.root.clone() }))
+    }
+}
+
+impl database::HistoryReader for History {
+    /// Send history of a key to client, from all segments that can contain
+    /// entries in the given time span.  Of sealed segments, only the ranges
+    /// given by their index are read.  For newest-first queries, segments are
+    /// read backwards until the limit is reached.
+    fn query_history(&self, key: &str, from: f64, to: f64, limit: HistLimit,
+                     send: &mut dyn FnMut(f64, &str)) {
+        let sealed = read_manifest(&self.root);
+        let mut segments = match list_segments(&self.root) {
+            Ok(segments) => segments,
+            Err(e) => {
+                warn!("could not list log segments: {}", e);
+                return;
+            }
+        };
+        if limit.newest_first {
+            segments.reverse();
+        }
+        let mut sender = LimitedSender::new(limit, send);
+        for seq in segments {
+            if let Some(segment) = sealed.get(&seq) {
+                if segment.times.1 < from || segment.times.0 > to {
+                    continue;
+                }
+            }
+            let mut values = Vec::new();
+            let mut send_matching = |_, _, parts: Vec<&str>| {
+                if parts[0] == key {
+                    let time = parts[1].parse().unwrap_or(0.);
+                    if from <= time && time <= to {
+                        values.push((time, if parts[3] == "-" { "" } else { parts[3] }.to_string()));
+                    }
+                }
+            };
+            if let Err(e) = self.read_ranges(seq, sealed.contains_key(&seq), key, from, to,
+                                             &mut send_matching) {
+                warn!("could not read log segment {}: {}", seq, e);
+            }
+            if !sender.send_chunk(values) {
+                break;
+            }
+        }
+    }
+}
+
+impl History {
+    /// Read the entries of a segment that can belong to the key and time span.
+    ///
+    /// The index is only used if it matches the segment, which it might not
+    /// while the segment is pruned.
+    fn read_ranges<F>(&self, seq: u64, sealed: bool, key: &str, from: f64, to: f64,
+                      f: &mut F) -> io::Result<()>
+    where F: FnMut(u64, u64, Vec<&str>)
+    {
+        let fp = File::open(segment_path(&self.root, seq))?;
+        if sealed {
+            let index = FileIndex::load(&index_path(&self.root, seq))?;
+            if index.covered > 0 && index.covered == fp.metadata()?.len() {
+                for (start, end) in index.ranges(key, from, to) {
+                    FlatStore::read_storefile_at(&fp, start, end, &mut *f)?;
+                }
+                return Ok(());
+            }
+        }
+        FlatStore::read_storefile_at(fp, 0, u64::MAX, f)?;
+        Ok(())
+    }
+}
+
+/// What retention does to the entries of a category in a sealed segment.
+#[derive(Clone, Copy, Hash, PartialEq)]
+enum Action {
+    Keep,
+    Thin,
+    Remove,
+}
+
+impl Action {
+    /// Decide by the newest entry of the segment, so that, like the days of
+    /// the flat-file store, segments are pruned as a whole.
+    fn new(rule: &Rule, max: f64, now: f64) -> Action {
+        if rule.keep_before(now).map_or(false, |t| max < t) {
+            Action::Remove
+        } else if rule.thins() && max < rule.raw_before(now) {
+            Action::Thin
+        } else {
+            Action::Keep
+        }
+    }
+}
+
+/// Removes old history from the sealed segments, concurrently with the store
+/// appending to the active one.
+pub struct Pruner {
+    /// Root path for the store files.
+    root: PathBuf,
+}
+
+impl database::HistoryPruner for Pruner {
+    /// Thin out or remove the entries of sealed segments.  Only segments that
+    /// are covered by the snapshot are pruned, and the entries that are in the
+    /// snapshot are always kept.
+    fn prune(&mut self, retention: &Retention, now: f64) -> io::Result<()> {
+        let mut latest = None;
+        let (mut nremoved, mut nthinned) = (0, 0);
+        for (seq, sealed) in read_manifest(&self.root) {
+            let actions = retention.rules().iter()
+                                   .map(|rule| Action::new(rule, sealed.times.1, now))
+                                   .collect::<Vec<_>>();
+            if actions.iter().all(|&action| action == Action::Keep) {
+                continue;
+            }
+            let mut hasher = DefaultHasher::new();
+            (retention.rules(), &actions).hash(&mut hasher);
+            let state = hasher.finish().max(1);
+            if state == sealed.pruned || !segment_path(&self.root, seq).is_file() {
+                continue;
+            }
+            if latest.is_none() {
+                latest = Some(self.read_snapshot()?);
+            }
+            let (snapshot_seq, latest) = latest.as_ref().unwrap();
+            if seq >= *snapshot_seq {
+                break;
+            }
+            match self.prune_segment(seq, retention, now, sealed.times.1, latest)? {
+                Some(times) => {
+                    append_manifest(&self.root, seq, Sealed { times, pruned: state })?;
+                    nthinned += 1;
+                }
+                None => nremoved += 1,
+            }
+        }
+        if nremoved + nthinned > 0 {
+            info!("pruned history: removed {} and thinned out {} log segments",
+                  nremoved, nthinned);
+        }
+        Ok(())
+    }
+}
+
+impl Pruner {
+    /// Read the first segment that is not covered by the snapshot, and the
+    /// time of each key's entry in the snapshot.
+    fn read_snapshot(&self) -> io::Result<(u64, HashMap<String, f64>)> {
+        let mut entry_map = EntryMap::default();
+        let next_seq = Store::new(self.root.clone()).load_snapshot(&mut entry_map)?;
+        let mut latest = HashMap::default();
+        for (catname, map) in entry_map {
+            for (subkey, entry) in map {
+                latest.insert(construct_key(&catname, &subkey), entry.time);
+            }
+        }
+        Ok((next_seq, latest))
+    }
+
+    /// Rewrite a sealed segment with only the entries that are kept, and
+    /// return its new time range, or None if it has been removed.
+    fn prune_segment(&self, seq: u64, retention: &Retention, now: f64, max: f64,
+                     latest: &HashMap<String, f64>) -> io::Result<Option<(f64, f64)>> {
+        let path = segment_path(&self.root, seq);
+        let content = fs::read(&path)?;
+        // for thinned out categories, the last entry of each key and day
+        let mut last = HashMap::<(String, String), (u64, u64, f64)>::default();
+        let mut keep = Vec::new();
+        FlatStore::read_storefile_at(Cursor::new(&content), 0, u64::MAX, |offset, n, parts| {
+            let time = parts[1].parse().unwrap_or(0.);
+            let action = retention.rule_for(split_key(parts[0]).0)
+                                  .map_or(Action::Keep, |rule| Action::new(rule, max, now));
+            if action == Action::Keep || latest.get(parts[0]) == Some(&time) {
+                keep.push((offset, n, time));
+            }
+            if action == Action::Thin {
+                let day = day_path(to_timespec(time));
+                let prev = last.entry((parts[0].to_string(), day)).or_insert((offset, n, time));
+                if time >= prev.2 {
+                    *prev = (offset, n, time);
+                }
+            }
+        })?;
+        keep.extend(last.into_values());
+        keep.sort_unstable_by_key(|&(offset, _, _)| offset);
+        keep.dedup_by_key(|&mut (offset, _, _)| offset);
+
+        // readers ignore an index that does not match the segment
+        let _ = remove_file(index_path(&self.root, seq));
+        if keep.is_empty() {
+            remove_file(&path)?;
+            return Ok(None);
+        }
+        let tmp_path = self.root.join(SEGMENT_DIR).join(".prune.tmp");
+        let mut fp = BufWriter::new(File::create(&tmp_path)?);
+        let mut index = FileIndex::default();
+        let mut times = (f64::INFINITY, f64::NEG_INFINITY);
+        let mut len = 0;
+        for (offset, n, time) in keep {
+            let line = &content[offset as usize..(offset + n) as usize];
+            fp.write_all(line)?;
+            let key = line.split(|&b| b == b'\t').next().unwrap_or_default();
+            index.add(&String::from_utf8_lossy(key), index_bucket(time), len, len + n);
+            times = (times.0.min(time), times.1.max(time));
+            len += n;
+        }
+        fp.into_inner()?.sync_all()?;
+        rename(tmp_path, &path)?;
+        index.append_to(&index_path(&self.root, seq), len)?;
+        Ok(Some(times))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Store as _;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn segments_and_snapshot() {
+        let dir = TempDir::new("log");
+        let root = dir.path().to_path_buf();
+        let mut store = Store::new(root.clone());
+        store.clear().unwrap();
+        let mut entry_map = EntryMap::default();
+        for i in 0..10 {
+            let entry = Entry::new(i as f64, 0., &i.to_string());
+            store.save("cat", "key", &entry).unwrap();
+            entry_map.entry_ref("cat").or_default().insert("key".into(), entry);
+        }
+        store.save("cat", "old", &Entry::new(1., 0., "x")).unwrap();
+        store.save("cat", "old", &Entry::new(2., 0., "")).unwrap();
+        store.rollover(10., &[&entry_map]).unwrap();
+        store.save("cat", "key", &Entry::new(10., 0., "10")).unwrap();
+        store.save("cat", "ttl", &Entry::new(11., 5., "y")).unwrap();
+        // simulate an interrupted write
+        store.active.as_mut().unwrap().file.write_all(b"cat/key\t12\t+").unwrap();
+        drop(store);
+
+        let mut store = Store::new(root.clone());
+        let mut entry_map = EntryMap::default();
+        store.load_latest(&mut entry_map).unwrap();
+        assert_eq!(entry_map["cat"]["key"].value, "10");
+        assert!(entry_map["cat"]["ttl"].expired);
+        assert!(!entry_map["cat"].contains_key("old"));
+        store.save("cat", "key", &Entry::new(13., 0., "13")).unwrap();
+
+        assert_eq!(read_manifest(&root).keys().copied().collect::<Vec<_>>(), [0]);
+        assert_eq!(FileIndex::load(&index_path(&root, 0)).unwrap().covered,
+                   segment_path(&root, 0).metadata().unwrap().len());
+        // the age of a segment is counted from its first entry
+        assert!(!store.needs_rollover(10. + SEGMENT_SPAN - 1.));
+        assert!(store.needs_rollover(10. + SEGMENT_SPAN));
+        let mut res = Vec::new();
+        store.history_reader().unwrap().query_history(
+            "cat/key", 8., 20., HistLimit::default(),
+            &mut |time, val| res.push((time, val.to_string())));
+        assert_eq!(res, [(8., "8".into()), (9., "9".into()),
+                         (10., "10".into()), (13., "13".into())]);
+        // newest first, across the sealed and the active segment
+        let mut res = Vec::new();
+        store.history_reader().unwrap().query_history(
+            "cat/key", 8., 20., HistLimit { count: Some(3), newest_first: true },
+            &mut |time, val| res.push((time, val.to_string())));
+        assert_eq!(res, [(13., "13".into()), (10., "10".into()), (9., "9".into())]);
+    }
+
+    #[test]
+    fn prune_segments() {
+        use crate::retention::Rule;
+
+        const DAY: f64 = 86400.;
+        let dir = TempDir::new("logprune");
+        let root = dir.path().to_path_buf();
+        let mut store = Store::new(root.clone());
+        store.clear().unwrap();
+        let mut entry_map = EntryMap::default();
+        let mut save = |store: &mut Store, key: &str, time: f64, value: &str| {
+            let (catname, subkey) = split_key(key);
+            let entry = Entry::new(time, 0., value);
+            store.save(catname, subkey, &entry).unwrap();
+            entry_map.entry_ref(catname).or_default().insert(subkey.into(), entry);
+            entry_map.clone()
+        };
+        // segment 0 has two days, segment 1 only a key that has expired
+        for i in 0..10 {
+            save(&mut store, "old/key", i as f64, &i.to_string());
+            save(&mut store, "new/key", i as f64, &i.to_string());
+        }
+        for i in 0..5 {
+            save(&mut store, "new/key", DAY + i as f64, &i.to_string());
+        }
+        let map = save(&mut store, "new/key", DAY + 5., "5");
+        store.rollover(DAY + 5., &[&map]).unwrap();
+        save(&mut store, "gone/key", 50. * DAY, "x");
+        let map = save(&mut store, "gone/key", 50. * DAY + 1., "");
+        store.rollover(50. * DAY + 1., &[&map]).unwrap();
+        save(&mut store, "new/key", 99. * DAY, "99");
+
+        // the age of a segment is counted from its first entry
+        assert!(!store.needs_rollover(100. * DAY - 1.));
+        assert!(store.needs_rollover(100. * DAY));
+        let seg0 = segment_path(&root, 0);
+        assert_eq!(FileIndex::load(&index_path(&root, 0)).unwrap().covered,
+                   seg0.metadata().unwrap().len());
+
+        let retention = Retention::new(vec![Rule::parse("old=7").unwrap(),
+                                            Rule::parse("new=7,daily").unwrap(),
+                                            Rule::parse("gone=7").unwrap()]);
+        let mut pruner = store.history_pruner().unwrap();
+        pruner.prune(&retention, 100. * DAY).unwrap();
+        assert_eq!(list_segments(&root).unwrap(), [0, 2]);
+        let sealed = read_manifest(&root)[&0];
+        assert_eq!(sealed.times, (9., DAY + 5.));
+        assert!(sealed.pruned != 0);
+        let content = fs::read(&seg0).unwrap();
+        pruner.prune(&retention, 100. * DAY + 3600.).unwrap();
+        assert_eq!(fs::read(&seg0).unwrap(), content);
+
+        let history = |key: &str| {
+            let mut res = Vec::new();
+            store.history_reader().unwrap().query_history(
+                key, 0., 200. * DAY, HistLimit::default(), &mut |time, _| res.push(time));
+            res
+        };
+        // the last value of each day, and the latest value is kept
+        assert_eq!(history("new/key"), [9., DAY + 5., 99. * DAY]);
+        assert_eq!(history("old/key"), [9.]);
+        assert_eq!(history("gone/key"), [] as [f64; 0]);
+    }
+}
@@ -0,0 +1,211 @@
+// -----------------------------------------------------------------------------
+// A Rust implementation of the NICOS cache server.
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// -----------------------------------------------------------------------------
+//
+//
+//! In-memory database store.
+
+use std::io::{self, BufWriter};
+use std::fs::{File, remove_file, rename};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::collections::VecDeque;
+use log::info;
+use parking_lot::RwLock;
+use hashbrown::HashMap;
+
+use crate::database::{self, EntryMap, HistLimit, LimitedSender};
+use crate::entry::{Entry, split_key, construct_key};
+use crate::store_flat::{Store as FlatStore, apply_stored};
+
+/// History rings of all keys, by full key.
+type Rings = HashMap<String, VecDeque<Entry>>;
+
+/// Represents the in-memory backend store.
+pub struct Store {
+    /// History rings, shared with the history reader.
+    rings:       Arc<RwLock<Rings>>,
+    /// Maximum number of entries kept per key.
+    history_len: usize,
+    /// File to dump a snapshot to on shutdown, and to load it from.
+    snapshot:    Option<PathBuf>,
+}
+
+/// Answers history queries from the rings, independent of the store.
+pub struct History {
+    rings:       Arc<RwLock<Rings>>,
+}
+
+impl Store {
+    pub fn new(snapshot: Option<PathBuf>, history_len: usize) -> Store {
+        Store { rings: Default::default(), history_len, snapshot }
+    }
+
+    /// Add an entry to the ring of a key, dropping the oldest entry if needed.
+    fn push(&self, rings: &mut Rings, key: &str, entry: Entry) {
+        let ring = rings.entry_ref(key).or_default();
+        if ring.len() >= self.history_len {
+            ring.pop_front();
+        }
+        ring.push_back(entry);
+    }
+}
+
+/// Collect the history of a key from the rings.
+fn query_history(rings: &RwLock<Rings>, key: &str, from: f64, to: f64, limit: HistLimit,
+                 send: &mut dyn FnMut(f64, &str)) {
+    // don't block saving new entries while sending
+    let entries = match rings.read().get(key) {
+        Some(ring) => ring.iter().filter(|entry| from <= entry.time && entry.time <= to)
+                                 .map(|entry| (entry.time, entry.value.clone()))
+                                 .collect(),
+        None => Vec::new(),
+    };
+    LimitedSender::new(limit, send).send_chunk(entries);
+}
+
+impl database::Store for Store {
+    /// Clear all rings and remove the snapshot.
+    fn clear(&mut self) -> io::Result<()> {
+        self.rings.write().clear();
+        if let Some(path) = &self.snapshot {
+            if path.is_file() {
+                remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the rings and latest entries from the snapshot, if present.
+    fn load_latest(&mut self, entry_map: &mut EntryMap) -> io::Result<()> {
+        let path = match &self.snapshot {
+            Some(path) if path.is_file() => path,
+            _ => return Ok(()),
+        };
+        let mut rings = self.rings.write();
+        let mut nentries = 0;
+        FlatStore::read_storefile_at(File::open(path)?, 0, u64::MAX, |_, _, parts| {
+            let (catname, subkey) = split_key(parts[0]);
+            apply_stored(entry_map.entry_ref(catname).or_insert_with(HashMap::default),
+                         subkey, &parts);
+            if let Ok(time) = parts[1].parse() {
+                let value = if parts[3] == "-" { "" } else { parts[3] };
+                self.push(&mut rings, parts[0], Entry::from_stored(time, parts[2] == "-", value));
+                nentries += 1;
+            }
+        })?;
+        info!("db: read {} history entries from snapshot", nentries);
+        Ok(())
+    }
+
+    /// Add a new key-value entry to the ring of its key.
+    fn save(&mut self, catname: &str, subkey: &str, entry: &Entry) -> io::Result<()> {
+        self.push(&mut self.rings.write(), &construct_key(catname, subkey), entry.clone());
+        Ok(())
+    }
+
+    /// Send history of a key to client.
+    fn query_history(&mut self, key: &str, from: f64, to: f64, limit: HistLimit,
+                     send: &mut dyn FnMut(f64, &str)) {
+        query_history(&self.rings, key, from, to, limit, send)
+    }
+
+    /// Send the entries of all rings, ordered by time.
+    fn export(&mut self, send: &mut dyn FnMut(&str, &Entry) -> io::Result<()>) -> io::Result<()> {
+        let rings = self.rings.read();
+        let mut entries = rings.iter().flat_map(|(key, ring)| ring.iter().map(move |e| (key, e)))
+                                      .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.1.time.total_cmp(&b.1.time));
+        for (key, entry) in entries {
+            send(key, entry)?;
+        }
+        Ok(())
+    }
+
+    /// The rings can be shared with a reader.
+    fn history_reader(&self) -> Option<Box<dyn database::HistoryReader>> {
+        Some(Box::new(History { rings: self.rings.clone() }))
+    }
+
+    /// Dump all rings to the snapshot file, if configured.
+    fn shutdown(&mut self) -> io::Result<()> {
+        let path = match &self.snapshot {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let tmp_path = path.with_extension("tmp");
+        let mut fp = BufWriter::new(File::create(&tmp_path)?);
+        for (key, ring) in self.rings.read().iter() {
+            for entry in ring {
+                entry.to_file(key, &mut fp)?;
+            }
+        }
+        fp.into_inner()?.sync_all()?;
+        rename(tmp_path, path)
+    }
+}
+
+impl database::HistoryReader for History {
+    /// Send history of a key to client.
+    fn query_history(&self, key: &str, from: f64, to: f64, limit: HistLimit,
+                     send: &mut dyn FnMut(f64, &str)) {
+        query_history(&self.rings, key, from, to, limit, send)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Store as _;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn rings_and_snapshot() {
+        let dir = TempDir::new("memory");
+        let path = dir.join("snapshot");
+        let mut store = Store::new(Some(path.clone()), 5);
+        for i in 0..10 {
+            store.save("cat", "key", &Entry::new(i as f64, 0., &i.to_string())).unwrap();
+        }
+        store.save("cat", "ttl", &Entry::new(3., 5., "x")).unwrap();
+        store.save("cat", "ttl", &Entry::new(4., 0., "")).unwrap();
+
+        let mut res = Vec::new();
+        store.history_reader().unwrap().query_history(
+            "cat/key", 0., 7., HistLimit::default(),
+            &mut |time, val| res.push((time, val.to_string())));
+        assert_eq!(res, [(5., "5".into()), (6., "6".into()), (7., "7".into())]);
+
+        store.shutdown().unwrap();
+        let mut store = Store::new(Some(path.clone()), 5);
+        let mut entry_map = EntryMap::default();
+        store.load_latest(&mut entry_map).unwrap();
+        assert_eq!(entry_map["cat"]["key"].value, "9");
+        assert!(entry_map["cat"]["ttl"].expired);
+        let mut res = Vec::new();
+        store.query_history("cat/ttl", 0., 10., HistLimit::default(),
+                            &mut |time, val| res.push((time, val.to_string())));
+        assert_eq!(res, [(3., "x".into()), (4., "".into())]);
+
+        store.clear().unwrap();
+        assert!(!path.exists());
+    }
+}
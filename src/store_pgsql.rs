@@ -23,22 +23,117 @@
 //! PostgreSQL-backed database store.
 
 use std::io;
-use log::info;
-use postgres::{self, Client, NoTls, error::Error};
+use log::{info, warn};
+use parking_lot::Mutex;
+use postgres::{self, Client, NoTls, error::Error, types::ToSql};
+use postgres::fallible_iterator::FallibleIterator;
 use hashbrown::HashMap;
 
-use crate::database::{self, EntryMap};
+use crate::database::{self, EntryMap, HistLimit};
 use crate::entry::{Entry, split_key, construct_key};
+use crate::retention::Retention;
+use crate::aggregate::{Aggregation, Function};
 
 /// Represents the Postgres backend store.
 pub struct Store {
     /// Postgres connection.
     connection: Client,
+    /// Connection URL, used to open further connections.
+    url: String,
 }
 
+/// Answers history queries on its own connection, so that they don't have to
+/// wait for the store (or vice versa).
+pub struct History {
+    /// Postgres connection.
+    connection: Mutex<Client>,
+}
+
+/// Removes old history on its own connection.
+pub struct Pruner {
+    /// Postgres connection.
+    connection: Client,
+}
+
+/// Functions used by the queries, which are (re)created on each start.
+const FUNCTIONS: &str =
+    "CREATE OR REPLACE FUNCTION cache_float8(value TEXT) RETURNS DOUBLE PRECISION AS $$ \
+       BEGIN RETURN value::float8; EXCEPTION WHEN others THEN RETURN NULL; END; \
+     $$ LANGUAGE plpgsql IMMUTABLE;";
+
 impl Store {
     pub fn new(url: &str) -> Result<Store, postgres::error::Error> {
-        Ok(Store { connection: Client::connect(url, NoTls)? })
+        let mut connection = Client::connect(url, NoTls)?;
+        connection.batch_execute(FUNCTIONS)?;
+        Ok(Store { connection, url: url.into() })
+    }
+}
+
+/// Send history of a key, queried on the given connection.
+fn query_history(connection: &mut Client, key: &str, from: f64, to: f64, limit: HistLimit,
+                 send: &mut dyn FnMut(f64, &str)) {
+    let query = if limit.newest_first {
+        "SELECT values.key, values.value, values.time FROM values \
+           WHERE key = $1 AND time >= $2 AND time <= $3 ORDER BY time DESC LIMIT $4;"
+    } else {
+        "SELECT values.key, values.value, values.time FROM values \
+           WHERE key = $1 AND time >= $2 AND time <= $3 ORDER BY time LIMIT $4;"
+    };
+    // a NULL limit returns all rows
+    let count = limit.count.map(|n| n as i64);
+    match connection.query(query, &[&key, &from, &to, &count]) {
+        Ok(result) => for row in &result {
+            let val: String = row.get(1);
+            send(row.get(2), &val);
+        },
+        Err(err) => warn!("could not query history of {}: {}", key, err),
+    }
+}
+
+/// Pattern for values that are aggregated as numbers, like those accepted by
+/// `aggregate::numeric`.
+const NUMBER_PATTERN: &str =
+    r"^[ \t\n\f\r]*[-+]?([0-9]+\.?[0-9]*|\.[0-9]+)([eE][-+]?[0-9]+)?[ \t\n\f\r]*$";
+
+/// SQL expression for the numeric value of `value` (given the pattern as a
+/// parameter), or NULL.  Values that can be out of range are cast by a
+/// function that catches the error, which is much slower than a plain cast.
+fn number_expr(pattern_param: &str) -> String {
+    format!("CASE WHEN value !~ {} THEN NULL \
+                  WHEN length(value) <= 300 AND value !~ '[eE]' THEN value::float8 \
+                  ELSE cache_float8(value) END", pattern_param)
+}
+
+/// Send aggregated history of a key, which is computed by the database.
+fn query_aggregated(connection: &mut Client, key: &str, from: f64, to: f64, agg: Aggregation,
+                    send: &mut dyn FnMut(f64, &str)) {
+    if agg.function == Function::Last {
+        let query = "SELECT floor((time - $2) / $4) AS bucket, \
+                       (array_agg(value ORDER BY time DESC))[1] FROM values \
+                       WHERE key = $1 AND time >= $2 AND time <= $3 \
+                       GROUP BY bucket ORDER BY bucket;";
+        match connection.query(query, &[&key, &from, &to, &agg.interval]) {
+            Ok(result) => for row in &result {
+                let val: String = row.get(1);
+                send(agg.bucket_start(from, row.get(0)), &val);
+            },
+            Err(err) => warn!("could not query aggregated history of {}: {}", key, err),
+        }
+    } else {
+        // the mean is computed exactly, so that large values cannot overflow
+        let query = format!("SELECT bucket, min(num), max(num), avg(num::numeric)::float8 FROM \
+                               ( SELECT floor((time - $2) / $4) AS bucket, {} AS num \
+                                   FROM values WHERE key = $1 AND time >= $2 AND time <= $3 ) AS v \
+                               GROUP BY bucket HAVING count(num) > 0 ORDER BY bucket;",
+                            number_expr("$5"));
+        let params: [&(dyn ToSql + Sync); 5] = [&key, &from, &to, &agg.interval, &NUMBER_PATTERN];
+        match connection.query(&*query, &params) {
+            Ok(result) => for row in &result {
+                agg.send_numeric(agg.bucket_start(from, row.get(0)),
+                                 row.get(1), row.get(2), row.get(3), send);
+            },
+            Err(err) => warn!("could not query aggregated history of {}: {}", key, err),
+        }
     }
 }
 
@@ -80,11 +175,6 @@ impl database::Store for Store {
         Ok(())
     }
 
-    /// Nothing to do here.
-    fn tell_hook(&mut self, _: &Entry, _: &mut EntryMap) -> io::Result<()> {
-        Ok(())
-    }
-
     /// Insert a new key-value entry.
     fn save(&mut self, catname: &str, subkey: &str, entry: &Entry) -> io::Result<()> {
         let query = "INSERT INTO values ( key, value, time, expires ) \
@@ -97,14 +187,143 @@ impl database::Store for Store {
     }
 
     /// Send history to client.
-    fn query_history(&mut self, key: &str, from: f64, to: f64, send: &mut dyn FnMut(f64, &str)) {
-        let query = "SELECT values.key, values.value, values.time FROM values \
-                       WHERE key = $1 AND time >= $2 AND time <= $3 ORDER BY time;";
-        if let Ok(result) = self.connection.query(query, &[&key, &from, &to]) {
-            for row in &result {
-                let val: String = row.get(1);
-                send(row.get(2), &val);
+    fn query_history(&mut self, key: &str, from: f64, to: f64, limit: HistLimit,
+                     send: &mut dyn FnMut(f64, &str)) {
+        query_history(&mut self.connection, key, from, to, limit, send)
+    }
+
+    /// Send aggregated history to client.
+    fn query_aggregated(&mut self, key: &str, from: f64, to: f64, agg: Aggregation,
+                        send: &mut dyn FnMut(f64, &str)) {
+        query_aggregated(&mut self.connection, key, from, to, agg, send)
+    }
+
+    /// Send all entries, ordered by time.
+    fn export(&mut self, send: &mut dyn FnMut(&str, &Entry) -> io::Result<()>) -> io::Result<()> {
+        let query = "SELECT key, value, time, expires FROM values ORDER BY time;";
+        let mut rows = self.connection.query_raw(query, std::iter::empty::<&str>())
+                                      .map_err(pg_err)?;
+        while let Some(row) = rows.next().map_err(pg_err)? {
+            let key: String = row.get(0);
+            let value: String = row.get(1);
+            send(&key, &Entry::from_stored(row.get(2), row.get(3), &value))?;
+        }
+        Ok(())
+    }
+
+    /// Open a further connection for pruning history.
+    fn history_pruner(&self) -> Option<Box<dyn database::HistoryPruner>> {
+        match Client::connect(&self.url, NoTls) {
+            Ok(connection) => Some(Box::new(Pruner { connection })),
+            Err(err) => {
+                warn!("could not open connection for pruning history: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Open a second connection for history queries.
+    fn history_reader(&self) -> Option<Box<dyn database::HistoryReader>> {
+        match Client::connect(&self.url, NoTls) {
+            Ok(connection) => Some(Box::new(History { connection: Mutex::new(connection) })),
+            Err(err) => {
+                warn!("could not open connection for history queries: {}", err);
+                None
             }
         }
     }
 }
+
+impl database::HistoryReader for History {
+    /// Send history to client.
+    fn query_history(&self, key: &str, from: f64, to: f64, limit: HistLimit,
+                     send: &mut dyn FnMut(f64, &str)) {
+        query_history(&mut self.connection.lock(), key, from, to, limit, send)
+    }
+
+    /// Send aggregated history to client.
+    fn query_aggregated(&self, key: &str, from: f64, to: f64, agg: Aggregation,
+                        send: &mut dyn FnMut(f64, &str)) {
+        query_aggregated(&mut self.connection.lock(), key, from, to, agg, send)
+    }
+}
+
+impl database::HistoryPruner for Pruner {
+    /// Delete old values, except for the latest of each key, and of each day
+    /// if the rule keeps daily values.
+    fn prune(&mut self, retention: &Retention, now: f64) -> io::Result<()> {
+        let mut ndeleted = 0;
+        for rule in retention.rules() {
+            let (filter, prefixes) = retention.sql_key_filter(rule, 3, |i| format!("${}", i));
+            // delete the values between two times that have a newer value
+            let mut delete = |newer: &str, from: f64, to: f64| {
+                let query = format!("DELETE FROM values v WHERE time >= $1 AND time < $2 AND {} \
+                                       AND EXISTS ( SELECT 1 FROM values w \
+                                         WHERE w.key = v.key AND w.time > v.time {} );",
+                                    filter, newer);
+                let mut params: Vec<&(dyn ToSql + Sync)> = vec![&from, &to];
+                params.extend(prefixes.iter().map(|p| p as &(dyn ToSql + Sync)));
+                self.connection.execute(&*query, &params).map_err(pg_err)
+            };
+            let keep_before = rule.keep_before(now);
+            if rule.thins() {
+                ndeleted += delete("AND w.time < v.time + 86400 AND \
+                                    date_trunc('day', to_timestamp(w.time)) = \
+                                    date_trunc('day', to_timestamp(v.time))",
+                                   keep_before.unwrap_or(f64::NEG_INFINITY), rule.raw_before(now))?;
+            }
+            if let Some(before) = keep_before {
+                ndeleted += delete("", f64::NEG_INFINITY, before)?;
+            }
+        }
+        if ndeleted > 0 {
+            info!("pruned history: deleted {} values", ndeleted);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use crate::aggregate::numeric;
+
+    /// Values on the edge of being numbers, for flat files and Postgres alike.
+    fn values() -> Vec<String> {
+        let mut values = ["1", " 2.5 ", "-3e2", "+.5", "1.", "1.e3", "0e500", "1e400", "-1e400",
+                          "1e-400", "1e-310", "\t7\n", "\u{a0}7", "\u{b}7", "inf", "NaN", "1e",
+                          ".", "", "x", "1,5", "1_0", "0x1A", "- 1"]
+            .iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        values.push("9".repeat(400));
+        values.push(format!("0.{}1", "0".repeat(400)));
+        values
+    }
+
+    #[test]
+    fn number_pattern() {
+        let pattern = Regex::new(NUMBER_PATTERN).unwrap();
+        for value in values() {
+            if numeric(&value).is_some() {
+                assert!(pattern.is_match(&value), "{:?}", value);
+            }
+        }
+        assert!(!pattern.is_match("\u{a0}7"));
+        assert!(pattern.is_match("1e400"));
+    }
+
+    /// Needs a database given by CACHE_RS_TEST_POSTGRES, otherwise it is skipped.
+    #[test]
+    fn numeric_values() {
+        let url = match std::env::var("CACHE_RS_TEST_POSTGRES") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let mut store = Store::new(&url).unwrap();
+        let query = format!("SELECT {} FROM (SELECT $1::text AS value) AS v;", number_expr("$2"));
+        for value in values() {
+            let row = store.connection.query_one(&*query, &[&value, &NUMBER_PATTERN]).unwrap();
+            assert_eq!(row.get::<_, Option<f64>>(0), numeric(&value), "{:?}", value);
+        }
+    }
+}
@@ -0,0 +1,305 @@
+// -----------------------------------------------------------------------------
+// A Rust implementation of the NICOS cache server.
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// -----------------------------------------------------------------------------
+//
+//
+//! SQLite-backed database store.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use parking_lot::Mutex;
+use rusqlite::{self, Connection, OpenFlags, ToSql, params};
+use hashbrown::HashMap;
+
+use crate::database::{self, EntryMap, HistLimit};
+use crate::entry::{Entry, split_key, construct_key};
+use crate::retention::Retention;
+
+/// Represents the SQLite backend store.
+pub struct Store {
+    /// SQLite connection.
+    connection: Connection,
+    /// Path of the database file, used to open further connections.
+    path: PathBuf,
+}
+
+/// Answers history queries on its own connection, so that they don't have to
+/// wait for the store (or vice versa).
+pub struct History {
+    /// SQLite connection.
+    connection: Mutex<Connection>,
+}
+
+/// Removes old history on its own connection.
+pub struct Pruner {
+    /// SQLite connection.
+    connection: Connection,
+}
+
+impl Store {
+    pub fn new(path: &Path) -> Result<Store, rusqlite::Error> {
+        let connection = Connection::open(path)?;
+        // with the write-ahead log, readers don't block the writer, and we
+        // don't need to sync the file for each new value
+        connection.query_row("PRAGMA journal_mode = WAL;", [], |_| Ok(()))?;
+        connection.execute_batch(
+            "PRAGMA synchronous = NORMAL; \
+             CREATE TABLE IF NOT EXISTS entries \
+               ( key TEXT, value TEXT, time REAL, expires INTEGER ); \
+             CREATE INDEX IF NOT EXISTS entries_key_time ON entries ( key, time );")?;
+        Ok(Store { connection, path: path.into() })
+    }
+}
+
+fn sql_err(err: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+/// Send history of a key, queried on the given connection.
+fn query_history(connection: &Connection, key: &str, from: f64, to: f64, limit: HistLimit,
+                 send: &mut dyn FnMut(f64, &str)) -> rusqlite::Result<()> {
+    let query = if limit.newest_first {
+        "SELECT value, time FROM entries \
+           WHERE key = ?1 AND time >= ?2 AND time <= ?3 ORDER BY time DESC LIMIT ?4;"
+    } else {
+        "SELECT value, time FROM entries \
+           WHERE key = ?1 AND time >= ?2 AND time <= ?3 ORDER BY time LIMIT ?4;"
+    };
+    // a negative limit returns all rows
+    let count = limit.count.map_or(-1, |n| n as i64);
+    let mut stmt = connection.prepare_cached(query)?;
+    let mut rows = stmt.query(params![key, from, to, count])?;
+    while let Some(row) = rows.next()? {
+        let val: String = row.get(0)?;
+        send(row.get(1)?, &val);
+    }
+    Ok(())
+}
+
+impl database::Store for Store {
+    /// Clear all DB values and recreate the schema.
+    fn clear(&mut self) -> io::Result<()> {
+        self.connection.execute_batch(
+            "DROP TABLE IF EXISTS entries; \
+             CREATE TABLE entries \
+               ( key TEXT, value TEXT, time REAL, expires INTEGER ); \
+             CREATE INDEX entries_key_time ON entries ( key, time );").map_err(sql_err)?;
+        Ok(())
+    }
+
+    /// Load the latest DB entries.
+    fn load_latest(&mut self, entry_map: &mut EntryMap) -> io::Result<()> {
+        // SQLite takes the other columns from the row with the maximum time
+        let query = "SELECT key, value, MAX(time), expires FROM entries GROUP BY key;";
+        let mut stmt = self.connection.prepare(query).map_err(sql_err)?;
+        let mut rows = stmt.query([]).map_err(sql_err)?;
+        let mut num_rows = 0;
+        while let Some(row) = rows.next().map_err(sql_err)? {
+            let key: String = row.get(0).map_err(sql_err)?;
+            let (cat, subkey) = split_key(&key);
+            let submap = entry_map.entry(cat.into()).or_insert_with(HashMap::default);
+            let mut entry = Entry::new_owned(row.get(2).map_err(sql_err)?, 0.,
+                                             row.get(1).map_err(sql_err)?);
+            if row.get(3).map_err(sql_err)? {
+                entry = entry.expired();
+            }
+            submap.insert(subkey.into(), entry);
+            num_rows += 1;
+        }
+        info!("db: read {} entries from SQLite database", num_rows);
+        Ok(())
+    }
+
+    /// Insert a new key-value entry.
+    fn save(&mut self, catname: &str, subkey: &str, entry: &Entry) -> io::Result<()> {
+        let query = "INSERT INTO entries ( key, value, time, expires ) \
+                       VALUES ( ?1, ?2, ?3, ?4 );";
+        let key = construct_key(catname, subkey);
+        let expires = entry.ttl > 0. || entry.expired;
+        self.connection.prepare_cached(query)
+                       .and_then(|mut stmt| stmt.execute(
+                           params![key, entry.value, entry.time, expires]))
+                       .map_err(sql_err)?;
+        Ok(())
+    }
+
+    /// Send history to client.
+    fn query_history(&mut self, key: &str, from: f64, to: f64, limit: HistLimit,
+                     send: &mut dyn FnMut(f64, &str)) {
+        if let Err(err) = query_history(&self.connection, key, from, to, limit, send) {
+            warn!("could not query history of {}: {}", key, err);
+        }
+    }
+
+    /// Send all entries, ordered by time.
+    fn export(&mut self, send: &mut dyn FnMut(&str, &Entry) -> io::Result<()>) -> io::Result<()> {
+        let query = "SELECT key, value, time, expires FROM entries ORDER BY time;";
+        let mut stmt = self.connection.prepare(query).map_err(sql_err)?;
+        let mut rows = stmt.query([]).map_err(sql_err)?;
+        while let Some(row) = rows.next().map_err(sql_err)? {
+            let key: String = row.get(0).map_err(sql_err)?;
+            let value: String = row.get(1).map_err(sql_err)?;
+            send(&key, &Entry::from_stored(row.get(2).map_err(sql_err)?,
+                                           row.get(3).map_err(sql_err)?, &value))?;
+        }
+        Ok(())
+    }
+
+    /// Open a further connection for pruning history.
+    fn history_pruner(&self) -> Option<Box<dyn database::HistoryPruner>> {
+        match Connection::open(&self.path) {
+            Ok(connection) => Some(Box::new(Pruner { connection })),
+            Err(err) => {
+                warn!("could not open connection for pruning history: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Open a second, read-only connection for history queries.
+    fn history_reader(&self) -> Option<Box<dyn database::HistoryReader>> {
+        match Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY) {
+            Ok(connection) => Some(Box::new(History { connection: Mutex::new(connection) })),
+            Err(err) => {
+                warn!("could not open connection for history queries: {}", err);
+                None
+            }
+        }
+    }
+}
+
+impl database::HistoryReader for History {
+    /// Send history to client.
+    fn query_history(&self, key: &str, from: f64, to: f64, limit: HistLimit,
+                     send: &mut dyn FnMut(f64, &str)) {
+        if let Err(err) = query_history(&self.connection.lock(), key, from, to, limit, send) {
+            warn!("could not query history of {}: {}", key, err);
+        }
+    }
+}
+
+impl database::HistoryPruner for Pruner {
+    /// Delete old values, except for the latest of each key, and of each day
+    /// if the rule keeps daily values.
+    fn prune(&mut self, retention: &Retention, now: f64) -> io::Result<()> {
+        let mut ndeleted = 0;
+        for rule in retention.rules() {
+            let (filter, prefixes) = retention.sql_key_filter(rule, 3, |i| format!("?{}", i));
+            // delete the values between two times that have a newer value
+            let delete = |newer: &str, from: f64, to: f64| {
+                let query = format!("DELETE FROM entries AS v WHERE time >= ?1 AND time < ?2 \
+                                       AND {} AND EXISTS ( SELECT 1 FROM entries AS w \
+                                         WHERE w.key = v.key AND w.time > v.time {} );",
+                                    filter, newer);
+                let mut params: Vec<&dyn ToSql> = vec![&from, &to];
+                params.extend(prefixes.iter().map(|p| p as &dyn ToSql));
+                self.connection.execute(&query, &*params).map_err(sql_err)
+            };
+            let keep_before = rule.keep_before(now);
+            if rule.thins() {
+                ndeleted += delete("AND w.time < v.time + 86400 AND \
+                                    date(w.time, 'unixepoch', 'localtime') = \
+                                    date(v.time, 'unixepoch', 'localtime')",
+                                   keep_before.unwrap_or(f64::NEG_INFINITY), rule.raw_before(now))?;
+            }
+            if let Some(before) = keep_before {
+                ndeleted += delete("", f64::NEG_INFINITY, before)?;
+            }
+        }
+        if ndeleted > 0 {
+            info!("pruned history: deleted {} values", ndeleted);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Store as _;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn save_and_query() {
+        let dir = TempDir::new("sqlite");
+        let mut store = Store::new(&dir.join("cache.db")).unwrap();
+        store.clear().unwrap();
+        for i in 0..10 {
+            store.save("cat", "key", &Entry::new(i as f64, 0., &i.to_string())).unwrap();
+        }
+        store.save("cat", "other", &Entry::new(5., 1., "x")).unwrap();
+        store.save("cat/sub", "key", &Entry::new(5., 0., "y")).unwrap();
+
+        let mut entry_map = EntryMap::default();
+        store.load_latest(&mut entry_map).unwrap();
+        assert_eq!(entry_map["cat"]["key"].value, "9");
+        assert_eq!(entry_map["cat"]["key"].time, 9.);
+        assert!(!entry_map["cat"]["key"].expired);
+        assert!(entry_map["cat"]["other"].expired);
+        assert_eq!(entry_map["cat/sub"]["key"].value, "y");
+
+        let history = store.history_reader().unwrap();
+        let mut res = Vec::new();
+        history.query_history("cat/key", 2.5, 5., HistLimit::default(),
+                              &mut |time, val| res.push((time, val.to_string())));
+        assert_eq!(res, [(3., "3".into()), (4., "4".into()), (5., "5".into())]);
+        let mut res = Vec::new();
+        history.query_history("cat/key", 0., 10., HistLimit { count: Some(2), newest_first: true },
+                              &mut |time, val| res.push((time, val.to_string())));
+        assert_eq!(res, [(9., "9".into()), (8., "8".into())]);
+    }
+
+    #[test]
+    fn prune() {
+        use crate::retention::Rule;
+
+        let dir = TempDir::new("sqlite-prune");
+        let mut store = Store::new(&dir.join("cache.db")).unwrap();
+        store.clear().unwrap();
+        let now = 1_600_000_000.;
+        for i in 0..40 {
+            let entry = Entry::new(now - i as f64 * 21600., 0., "x");
+            for cat in ["cat", "cat/sub", "other"] {
+                store.save(cat, "key", &entry).unwrap();
+            }
+        }
+        store.save("cat", "old", &Entry::new(now - 864000., 0., "x")).unwrap();
+
+        let retention = Retention::new(vec![Rule::parse("cat=2,daily=5").unwrap(),
+                                            Rule::parse("cat/sub=1").unwrap()]);
+        store.history_pruner().unwrap().prune(&retention, now).unwrap();
+        let count = |key: &str, from: f64, to: f64| -> (u32, u32) {
+            store.connection.query_row(
+                "SELECT COUNT(*), COUNT(DISTINCT date(time, 'unixepoch', 'localtime')) \
+                   FROM entries WHERE key = ?1 AND time >= ?2 AND time < ?3;",
+                params![key, from, to], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+        };
+        let (day, inf) = (86400., f64::INFINITY);
+        assert_eq!(count("cat/key", now - 2. * day, inf).0, 9);
+        let (n, ndays) = count("cat/key", now - 5. * day, now - 2. * day);
+        assert!(n >= 3 && n == ndays);
+        assert_eq!(count("cat/key", -inf, now - 5. * day).0, 0);
+        assert_eq!(count("cat/old", -inf, inf).0, 1);
+        assert_eq!(count("cat/sub/key", -inf, inf).0, 5);
+        assert_eq!(count("other/key", -inf, inf).0, 40);
+    }
+}
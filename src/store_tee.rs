@@ -0,0 +1,371 @@
+// -----------------------------------------------------------------------------
+// A Rust implementation of the NICOS cache server.
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// -----------------------------------------------------------------------------
+//
+//
+//! Database store that mirrors writes to several backends.
+
+use std::{io, thread};
+use std::time::Duration;
+use crossbeam_channel::{bounded, Sender, TrySendError};
+use log::{info, warn};
+
+use crate::database::{self, EntryMap, HistLimit, HistoryReader, HistoryPruner};
+use crate::retention::Retention;
+use crate::entry::Entry;
+use crate::aggregate::Aggregation;
+
+/// Number of operations that can wait for a mirror before writes are dropped.
+const MIRROR_QUEUE_LEN: usize = 10000;
+
+/// How long to wait for a mirror to finish writing on shutdown.
+const MIRROR_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An operation sent to the writer thread of a mirror.
+enum MirrorOp {
+    Clear,
+    Load(EntryMap),
+    Save(String, String, Entry),
+    Pruner(Sender<Option<Box<dyn HistoryPruner>>>),
+    Shutdown(Sender<()>),
+}
+
+/// A secondary store, which is written but never read.
+///
+/// The store itself lives in a writer thread, so that a slow mirror cannot
+/// hold up the primary store.
+struct Mirror {
+    /// Name for reporting errors.
+    name:    String,
+    queue:   Sender<MirrorOp>,
+    /// Number of writes dropped since the queue last overflowed.
+    dropped: usize,
+}
+
+impl Mirror {
+    fn new(name: String, store: Box<dyn database::Store>) -> Mirror {
+        let (queue, ops) = bounded(MIRROR_QUEUE_LEN);
+        let thread_name = name.clone();
+        thread::spawn(move || {
+            let mut writer = MirrorWriter { name: thread_name, store, latest: EntryMap::default(),
+                                            failing: false };
+            for op in ops {
+                match op {
+                    MirrorOp::Pruner(reply) => {
+                        let _ = reply.send(writer.store.history_pruner());
+                    }
+                    MirrorOp::Shutdown(done) => {
+                        writer.run("shutdown", |store, _| store.shutdown());
+                        let _ = done.send(());
+                        return;
+                    }
+                    op => writer.handle(op),
+                }
+            }
+        });
+        Mirror { name, queue, dropped: 0 }
+    }
+
+    /// Queue an operation, dropping it if the mirror is too far behind.
+    fn send(&mut self, op: MirrorOp) {
+        match self.queue.try_send(op) {
+            Ok(()) if self.dropped > 0 => {
+                info!("{}: catching up again after dropping {} writes", self.name, self.dropped);
+                self.dropped = 0;
+            }
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    warn!("{}: too far behind, dropping writes", self.name);
+                }
+                self.dropped += 1;
+            }
+            // the writer thread only exits on shutdown
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+    }
+
+    /// Get a pruner for the mirror's store from its writer thread.
+    fn history_pruner(&self) -> Option<Box<dyn HistoryPruner>> {
+        let (reply, wait) = bounded(1);
+        if self.queue.send_timeout(MirrorOp::Pruner(reply), MIRROR_SHUTDOWN_TIMEOUT).is_err() {
+            return None;
+        }
+        wait.recv_timeout(MIRROR_SHUTDOWN_TIMEOUT).ok().flatten()
+    }
+
+    /// Let the writer finish the queued operations and shut down the store.
+    fn shutdown(&self) {
+        let (done, wait) = bounded(1);
+        if self.queue.send_timeout(MirrorOp::Shutdown(done), MIRROR_SHUTDOWN_TIMEOUT).is_err() ||
+            wait.recv_timeout(MIRROR_SHUTDOWN_TIMEOUT).is_err()
+        {
+            warn!("{}: could not finish writing in time", self.name);
+        }
+    }
+}
+
+/// The writer thread's side of a mirror.
+struct MirrorWriter {
+    name:    String,
+    store:   Box<dyn database::Store>,
+    /// The latest entries, which the store needs to roll over on its own.
+    latest:  EntryMap,
+    /// Did the last operation fail?  Used to report failures only once.
+    failing: bool,
+}
+
+impl MirrorWriter {
+    fn handle(&mut self, op: MirrorOp) {
+        match op {
+            MirrorOp::Clear => {
+                self.latest.clear();
+                self.run("clear", |store, _| store.clear());
+            }
+            MirrorOp::Load(entry_map) => {
+                self.run("load", |store, _| store.load_latest(&mut EntryMap::default()));
+                self.latest = entry_map;
+            }
+            MirrorOp::Save(catname, subkey, entry) => {
+                if self.store.needs_rollover(entry.time) {
+                    self.run("rollover", |store, latest| store.rollover(entry.time, &[latest]));
+                }
+                self.run("save", |store, _| store.save(&catname, &subkey, &entry));
+                self.latest.entry(catname).or_default().insert(subkey, entry);
+            }
+            MirrorOp::Pruner(_) | MirrorOp::Shutdown(_) => unreachable!(),
+        }
+    }
+
+    /// Run an operation on the store and report changes in its success.
+    fn run<F>(&mut self, what: &str, f: F)
+    where F: FnOnce(&mut dyn database::Store, &EntryMap) -> io::Result<()>
+    {
+        match f(&mut *self.store, &self.latest) {
+            Ok(()) if self.failing => {
+                info!("{}: {} works again", self.name, what);
+                self.failing = false;
+            }
+            Ok(()) => (),
+            Err(e) if !self.failing => {
+                warn!("{}: {} failed: {}", self.name, what, e);
+                self.failing = true;
+            }
+            Err(_) => (),
+        }
+    }
+}
+
+/// Prunes the history of the primary and all mirrors that support it.
+struct Pruner {
+    primary: Option<Box<dyn HistoryPruner>>,
+    mirrors: Vec<(String, Box<dyn HistoryPruner>)>,
+}
+
+impl HistoryPruner for Pruner {
+    /// Errors of the mirrors are reported, but only those of the primary are
+    /// returned.
+    fn prune(&mut self, retention: &Retention, now: f64) -> io::Result<()> {
+        for (name, pruner) in &mut self.mirrors {
+            if let Err(e) = pruner.prune(retention, now) {
+                warn!("{}: could not prune old history: {}", name, e);
+            }
+        }
+        match &mut self.primary {
+            Some(pruner) => pruner.prune(retention, now),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Represents the mirroring store.
+///
+/// All entries are written to the primary and queued for all mirrors, but
+/// loading and history come only from the primary.  Each mirror is written by
+/// its own thread and rolls over on its own; if it falls too far behind, writes
+/// to it are dropped.  Errors of the mirrors are reported, but never returned.
+pub struct Store {
+    primary: Box<dyn database::Store>,
+    mirrors: Vec<Mirror>,
+}
+
+impl Store {
+    pub fn new(primary: Box<dyn database::Store>,
+               mirrors: Vec<(String, Box<dyn database::Store>)>) -> Store {
+        Store {
+            primary,
+            mirrors: mirrors.into_iter().map(|(name, store)| Mirror::new(name, store)).collect(),
+        }
+    }
+}
+
+impl database::Store for Store {
+    /// Clear all stores.
+    fn clear(&mut self) -> io::Result<()> {
+        let result = self.primary.clear();
+        for mirror in &mut self.mirrors {
+            mirror.send(MirrorOp::Clear);
+        }
+        result
+    }
+
+    /// Load the latest entries from the primary.  The mirrors are loaded too,
+    /// so that they can continue writing, but their entries are ignored.
+    fn load_latest(&mut self, entry_map: &mut EntryMap) -> io::Result<()> {
+        let result = self.primary.load_latest(entry_map);
+        for mirror in &mut self.mirrors {
+            mirror.send(MirrorOp::Load(entry_map.clone()));
+        }
+        result
+    }
+
+    /// Only the primary is rolled over here, the mirrors do it themselves.
+    fn needs_rollover(&self, time: f64) -> bool {
+        self.primary.needs_rollover(time)
+    }
+
+    fn rollover(&mut self, time: f64, entry_maps: &[&EntryMap]) -> io::Result<()> {
+        self.primary.rollover(time, entry_maps)
+    }
+
+    /// Save the entry to all stores.
+    fn save(&mut self, catname: &str, subkey: &str, entry: &Entry) -> io::Result<()> {
+        let result = self.primary.save(catname, subkey, entry);
+        for mirror in &mut self.mirrors {
+            mirror.send(MirrorOp::Save(catname.into(), subkey.into(), entry.clone()));
+        }
+        result
+    }
+
+    /// Query history from the primary.
+    fn query_history(&mut self, key: &str, from: f64, to: f64, limit: HistLimit,
+                     send: &mut dyn FnMut(f64, &str)) {
+        self.primary.query_history(key, from, to, limit, send)
+    }
+
+    /// Query aggregated history from the primary.
+    fn query_aggregated(&mut self, key: &str, from: f64, to: f64, agg: Aggregation,
+                        send: &mut dyn FnMut(f64, &str)) {
+        self.primary.query_aggregated(key, from, to, agg, send)
+    }
+
+    /// Export from the primary.
+    fn export(&mut self, send: &mut dyn FnMut(&str, &Entry) -> io::Result<()>) -> io::Result<()> {
+        self.primary.export(send)
+    }
+
+    fn history_reader(&self) -> Option<Box<dyn HistoryReader>> {
+        self.primary.history_reader()
+    }
+
+    /// Prune the primary and all mirrors, as far as they support it.
+    fn history_pruner(&self) -> Option<Box<dyn HistoryPruner>> {
+        let primary = self.primary.history_pruner();
+        let mirrors = self.mirrors.iter().filter_map(|mirror| {
+            mirror.history_pruner().map(|pruner| (mirror.name.clone(), pruner))
+        }).collect::<Vec<_>>();
+        if primary.is_none() && mirrors.is_empty() {
+            return None;
+        }
+        Some(Box::new(Pruner { primary, mirrors }))
+    }
+
+    /// Shut down all stores.
+    fn shutdown(&mut self) -> io::Result<()> {
+        let result = self.primary.shutdown();
+        for mirror in &self.mirrors {
+            mirror.shutdown();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crossbeam_channel::{bounded, Receiver};
+    use crate::database::{EntryMap, HistLimit, Store as _};
+    use crate::entry::Entry;
+    use crate::{store_flat, store_memory};
+    use crate::testutil::TempDir;
+    use super::{Store, MIRROR_QUEUE_LEN};
+
+    /// A mirror that hangs on saving until it is released.
+    struct Stuck {
+        release: Receiver<()>,
+        saved:   Arc<AtomicUsize>,
+    }
+
+    impl crate::database::Store for Stuck {
+        fn clear(&mut self) -> std::io::Result<()> { Ok(()) }
+        fn load_latest(&mut self, _: &mut EntryMap) -> std::io::Result<()> { Ok(()) }
+        fn save(&mut self, _: &str, _: &str, _: &Entry) -> std::io::Result<()> {
+            let _ = self.release.recv();
+            self.saved.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+        fn query_history(&mut self, _: &str, _: f64, _: f64, _: HistLimit,
+                         _: &mut dyn FnMut(f64, &str)) {}
+        fn export(&mut self, _: &mut dyn FnMut(&str, &Entry) -> std::io::Result<()>)
+                  -> std::io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn slow_mirror() {
+        let (release_q, release) = bounded(0);
+        let saved = Arc::new(AtomicUsize::new(0));
+        let mirror = Stuck { release, saved: saved.clone() };
+        let mut store = Store::new(Box::new(store_memory::Store::new(None, 10)),
+                                   vec![("stuck".into(), Box::new(mirror))]);
+
+        // saving must not wait for the mirror, even when its queue is full
+        let count = MIRROR_QUEUE_LEN + 100;
+        for i in 0..count {
+            store.save("cat", "key", &Entry::new(i as f64, 0., &i.to_string())).unwrap();
+        }
+        let mut history = Vec::new();
+        store.query_history("cat/key", 0., f64::INFINITY, HistLimit::default(),
+                            &mut |_, val| history.push(val.to_string()));
+        assert_eq!(history.len(), 10);
+        assert_eq!(history[9], (count - 1).to_string());
+
+        // the writes that fit into the queue still arrive
+        drop(release_q);
+        store.shutdown().unwrap();
+        let saved = saved.load(Ordering::SeqCst);
+        assert!((MIRROR_QUEUE_LEN..=MIRROR_QUEUE_LEN + 1).contains(&saved));
+    }
+
+    #[test]
+    fn pruner() {
+        let dir = TempDir::new("tee-prune");
+        let flat = |name| Box::new(store_flat::Store::new(dir.join(name), false, None));
+        let store = Store::new(Box::new(store_memory::Store::new(None, 10)), vec![]);
+        assert!(store.history_pruner().is_none());
+        // the primary cannot prune, but the mirror can
+        let store = Store::new(Box::new(store_memory::Store::new(None, 10)),
+                               vec![("flat".into(), flat("mirror"))]);
+        assert!(store.history_pruner().is_some());
+        let store = Store::new(flat("primary"), vec![("flat".into(), flat("mirror"))]);
+        assert!(store.history_pruner().is_some());
+    }
+}
@@ -0,0 +1,250 @@
+// -----------------------------------------------------------------------------
+// A Rust implementation of the NICOS cache server.
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// -----------------------------------------------------------------------------
+//
+//! Options of subscriptions, holding back updates of rate-limited ones and
+//! filtering updates within a deadband.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use hashbrown::HashMap;
+
+use crate::entry::UpdaterEntry;
+use crate::aggregate::numeric;
+
+/// Minimum change of a numeric value for an update to be sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Deadband {
+    Absolute(f64),
+    /// A fraction of the last sent value.
+    Relative(f64),
+}
+
+impl Deadband {
+    /// Parse an absolute deadband, or a relative one given in percent.
+    fn parse(spec: &str) -> Result<Deadband, String> {
+        let spec = spec.trim();
+        let (number, relative) = match spec.strip_suffix('%') {
+            Some(number) => (number, true),
+            None => (spec, false),
+        };
+        match number.trim().parse::<f64>() {
+            Ok(v) if v >= 0. && v.is_finite() && relative => Ok(Deadband::Relative(v / 100.)),
+            Ok(v) if v >= 0. && v.is_finite() => Ok(Deadband::Absolute(v)),
+            _ => Err(format!("invalid deadband {:?}", spec)),
+        }
+    }
+
+    /// Check if a value differs enough from the last sent one.
+    fn exceeded(self, last: f64, value: f64) -> bool {
+        let diff = (value - last).abs();
+        match self {
+            Deadband::Absolute(band) => diff >= band,
+            Deadband::Relative(band) => diff >= band * last.abs(),
+        }
+    }
+}
+
+/// Options that can be given with a subscription, as `key:[opt,opt]`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SubOptions {
+    /// Minimum interval between updates of a key, from the maximum rate.
+    pub interval: Option<Duration>,
+    /// Minimum change of numeric values.
+    pub deadband: Option<Deadband>,
+    /// Send the matching entries before the updates.
+    pub snapshot: bool,
+}
+
+impl SubOptions {
+    /// Parse a comma-separated list of options: `rate=N` sends at most N
+    /// updates per second and key, `deadband=X` or `deadband=X%` only sends
+    /// numeric values that changed by at least X (percent), `snapshot` sends
+    /// the matching entries first.
+    pub fn parse(spec: &str) -> Result<SubOptions, String> {
+        let mut opts = SubOptions::default();
+        for token in spec.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            match token.split_once('=') {
+                Some(("rate", rate)) => match rate.trim().parse::<f64>() {
+                    Ok(rate) if rate > 0. && rate.is_finite() =>
+                        opts.interval = Some(Duration::from_secs_f64(1. / rate)),
+                    _ => return Err(format!("invalid update rate {:?}", rate)),
+                },
+                Some(("deadband", band)) => opts.deadband = Some(Deadband::parse(band)?),
+                None if token == "snapshot" => opts.snapshot = true,
+                _ => return Err(format!("invalid subscription option {:?}", token)),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// State of a key whose updates are rate limited.
+struct Limited {
+    interval:  Duration,
+    last_sent: Instant,
+    pending:   Option<Arc<UpdaterEntry>>,
+}
+
+/// Holds back updates of rate-limited subscriptions, so that at most one
+/// update per key and interval is sent.  The newest held-back update is sent
+/// once the interval is over, so that the client always gets the final value.
+#[derive(Default)]
+pub struct RateLimiter {
+    keys:     HashMap<String, Limited>,
+    next_due: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Check if an update can be sent now; otherwise it is held back.
+    pub fn admit(&mut self, entry: &Arc<UpdaterEntry>, interval: Duration, now: Instant) -> bool {
+        match self.keys.get_mut(entry.key()) {
+            Some(state) if now < state.last_sent + interval => {
+                state.interval = interval;
+                state.pending = Some(entry.clone());
+                let due = state.last_sent + interval;
+                self.next_due = Some(self.next_due.map_or(due, |next| next.min(due)));
+                false
+            }
+            Some(state) => {
+                *state = Limited { interval, last_sent: now, pending: None };
+                true
+            }
+            None => {
+                self.keys.insert(entry.key().into(), Limited { interval, last_sent: now,
+                                                               pending: None });
+                true
+            }
+        }
+    }
+
+    /// Get the time when the next held-back update is due.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.next_due
+    }
+
+    /// Take the held-back updates that are due.  Keys that have been quiet
+    /// for their interval are forgotten.
+    pub fn take_due(&mut self, now: Instant) -> Vec<Arc<UpdaterEntry>> {
+        let mut due = Vec::new();
+        if self.next_due.map_or(true, |next| next > now) {
+            return due;
+        }
+        let mut next_due = None::<Instant>;
+        self.keys.retain(|_, state| {
+            if state.pending.is_some() {
+                if state.last_sent + state.interval <= now {
+                    due.extend(state.pending.take());
+                    state.last_sent = now;
+                } else {
+                    let at = state.last_sent + state.interval;
+                    next_due = Some(next_due.map_or(at, |next| next.min(at)));
+                }
+            }
+            state.pending.is_some() || now < state.last_sent + state.interval
+        });
+        self.next_due = next_due;
+        due
+    }
+}
+
+/// Suppresses updates of numeric values that are within a deadband of the
+/// value last sent for the key.
+#[derive(Default)]
+pub struct DeadbandFilter {
+    last: HashMap<String, f64>,
+}
+
+impl DeadbandFilter {
+    /// Check if an update should be sent.  Non-numeric values and expirations
+    /// are always sent.
+    pub fn admit(&mut self, entry: &UpdaterEntry, deadband: Deadband) -> bool {
+        let value = match numeric(&entry.entry().value) {
+            Some(value) if !entry.entry().expired => value,
+            _ => {
+                self.last.remove(entry.key());
+                return true;
+            }
+        };
+        match self.last.get_mut(entry.key()) {
+            Some(last) if !deadband.exceeded(*last, value) => false,
+            Some(last) => {
+                *last = value;
+                true
+            }
+            None => {
+                self.last.insert(entry.key().into(), value);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::Entry;
+
+    #[test]
+    fn rate_limit() {
+        assert_eq!(SubOptions::parse("rate=4").unwrap().interval, Some(Duration::from_millis(250)));
+        assert_eq!(SubOptions::parse("").unwrap(), SubOptions::default());
+        assert!(SubOptions::parse("rate=0").is_err());
+        assert!(SubOptions::parse("speed=1").is_err());
+        assert!(SubOptions::parse("snapshot").unwrap().snapshot);
+
+        let entry = |val| Arc::new(UpdaterEntry::new("key".into(), &Entry::new(0., 0., val), 0));
+        let interval = Duration::from_secs(1);
+        let start = Instant::now();
+        let mut limiter = RateLimiter::default();
+        assert!(limiter.admit(&entry("1"), interval, start));
+        assert!(!limiter.admit(&entry("2"), interval, start + interval / 4));
+        assert!(!limiter.admit(&entry("3"), interval, start + interval / 2));
+        assert_eq!(limiter.next_due(), Some(start + interval));
+        assert!(limiter.take_due(start + interval / 2).is_empty());
+        let due = limiter.take_due(start + interval);
+        assert_eq!(due.iter().map(|e| e.get_msg(false, false)).collect::<Vec<_>>(), ["key=3\n"]);
+        assert_eq!(limiter.next_due(), None);
+        // the final update also starts a new interval
+        assert!(!limiter.admit(&entry("4"), interval, start + interval * 3 / 2));
+        assert_eq!(limiter.take_due(start + interval * 2).len(), 1);
+        assert!(limiter.admit(&entry("5"), interval, start + interval * 4));
+    }
+
+    #[test]
+    fn deadband() {
+        assert_eq!(SubOptions::parse("deadband=0.5").unwrap().deadband,
+                   Some(Deadband::Absolute(0.5)));
+        assert_eq!(SubOptions::parse("rate=1, deadband=2%").unwrap().deadband,
+                   Some(Deadband::Relative(0.02)));
+        assert!(SubOptions::parse("deadband=-1").is_err());
+
+        let entry = |val| UpdaterEntry::new("key".into(), &Entry::new(0., 0., val), 0);
+        let mut filter = DeadbandFilter::default();
+        let band = Deadband::Relative(0.01);
+        let passed = ["100.0", "100.5", "98.9", "99.3", "moving", "99.3", "99.31"]
+            .into_iter().filter(|val| filter.admit(&entry(val), band)).collect::<Vec<_>>();
+        assert_eq!(passed, ["100.0", "98.9", "moving", "99.3"]);
+        let mut expired = Entry::new(0., 0., "99.3");
+        expired.expired = true;
+        assert!(filter.admit(&UpdaterEntry::new("key".into(), &expired, 0), band));
+    }
+}